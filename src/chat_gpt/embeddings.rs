@@ -0,0 +1,83 @@
+use anyhow::Context;
+use serde_json::{json, Value};
+
+use crate::chat_gpt::ChatGptClient;
+use crate::config::EmbeddingsConfig;
+
+/// Most texts embedded in one request, as endpoints limit how many inputs a request can have.
+const BATCH_SIZE: usize = 128;
+
+/// Embeds each of `texts` through the configured `/embeddings` compatible endpoint, or returns
+/// `None` when embeddings aren't configured.
+pub async fn embed(
+    chat_gpt: &ChatGptClient,
+    texts: &[String],
+) -> anyhow::Result<Option<Vec<Vec<f32>>>> {
+    let Some(config) = &chat_gpt.embeddings else {
+        return Ok(None);
+    };
+    let mut embeddings = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(BATCH_SIZE) {
+        embeddings.extend(embed_batch(chat_gpt, config, batch).await?);
+    }
+    Ok(Some(embeddings))
+}
+
+/// The embeddings of `texts`, in the same order, from a single request.
+async fn embed_batch(
+    chat_gpt: &ChatGptClient,
+    config: &EmbeddingsConfig,
+    texts: &[String],
+) -> anyhow::Result<Vec<Vec<f32>>> {
+    let api_key = config.api_key.as_ref().unwrap_or(&chat_gpt.config.api_key);
+    let request = json!({
+        "model": config.model,
+        "input": texts,
+    });
+
+    let response = chat_gpt
+        .http
+        .post(config.url.clone())
+        .bearer_auth(api_key)
+        .json(&request)
+        .send()
+        .await
+        .context("Cannot reach the embeddings service")?;
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .context("Cannot read embeddings response")?;
+    if !status.is_success() {
+        anyhow::bail!("Embeddings request failed with {status}: {body}");
+    }
+    let res: Value = serde_json::from_str(&body).context("Invalid embeddings response")?;
+
+    let data = res
+        .get("data")
+        .and_then(|data| data.as_array())
+        .context("No data")?;
+    if data.len() != texts.len() {
+        anyhow::bail!("Got {} embeddings for {} texts", data.len(), texts.len());
+    }
+    let mut embeddings = vec![vec![]; texts.len()];
+    for (i, item) in data.iter().enumerate() {
+        // Items carry their input's index, which the API doesn't promise to keep in order.
+        let index = item
+            .get("index")
+            .and_then(|index| index.as_u64())
+            .map_or(i, |index| index as usize);
+        let embedding = item
+            .get("embedding")
+            .and_then(|embedding| embedding.as_array())
+            .context("No embedding")?
+            .iter()
+            .map(|value| value.as_f64().map(|value| value as f32))
+            .collect::<Option<Vec<_>>>()
+            .context("Invalid embedding")?;
+        *embeddings
+            .get_mut(index)
+            .context("Invalid embedding index")? = embedding;
+    }
+    Ok(embeddings)
+}
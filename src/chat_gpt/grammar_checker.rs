@@ -1,13 +1,23 @@
-use openai_chatgpt_api::ChatGptChatFormat;
+use crate::chat_gpt::ChatMessage;
+use crate::utils::options::{parse_args, OptionSpec, UsageError};
 
-use crate::chat_gpt::{ask_chat_gpt, split_options_and_body};
-
-pub async fn check_grammar(open_api_token: &str, user_input: String) -> anyhow::Result<String> {
-    let (lang, text) = split_options_and_body(user_input, "Chinese".to_string(), 'l');
-    let conversation_history: Vec<ChatGptChatFormat> = vec![
-        ChatGptChatFormat::new_system(&format!("You are a language teacher, diagnose grammar problems for me and explain them to me in {lang}.")),
-        ChatGptChatFormat::new_user(&text),
-    ];
+/// The options `/gramcheck` takes.
+pub fn grammar_options(default_lang: &str) -> Vec<OptionSpec> {
+    vec![
+        OptionSpec::value("lang", Some('l'), "Language to explain the problems in")
+            .with_default(default_lang),
+    ]
+}
 
-    ask_chat_gpt(open_api_token, conversation_history).await
+/// The conversation asking ChatGPT to check the grammar of the text after the options.
+pub fn check_grammar(default_lang: &str, user_input: &str) -> Result<Vec<ChatMessage>, UsageError> {
+    let args = parse_args(&grammar_options(default_lang), user_input)?;
+    if args.body.is_empty() {
+        return Err(UsageError("Nothing to check".to_string()));
+    }
+    let lang = args.value("lang").unwrap_or(default_lang);
+    Ok(vec![
+        ChatMessage::new_system(&format!("You are a language teacher, diagnose grammar problems for me and explain them to me in {lang}.")),
+        ChatMessage::new_user(&args.body),
+    ])
 }
@@ -0,0 +1,88 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+/// A message of a conversation with ChatGPT. Plain text messages serialize like the API's
+/// string content, so stored sessions keep their format.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: MessageContent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    /// Text and images, for vision models.
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// An image given by URL, or inline as a `data:` URL.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: &str) -> Self {
+        ChatMessage {
+            role: role.to_string(),
+            content: MessageContent::Text(content.to_string()),
+        }
+    }
+    pub fn new_system(content: &str) -> Self {
+        ChatMessage::new("system", content)
+    }
+    pub fn new_user(content: &str) -> Self {
+        ChatMessage::new("user", content)
+    }
+    pub fn new_assistant(content: &str) -> Self {
+        ChatMessage::new("assistant", content)
+    }
+
+    /// A user message with JPEG images, such as Telegram photos, sent inline.
+    pub fn new_user_with_images(content: &str, images: &[Vec<u8>]) -> Self {
+        let mut parts = vec![];
+        if !content.is_empty() {
+            parts.push(ContentPart::Text {
+                text: content.to_string(),
+            });
+        }
+        for image in images {
+            let data = base64::engine::general_purpose::STANDARD.encode(image);
+            parts.push(ContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: format!("data:image/jpeg;base64,{data}"),
+                },
+            });
+        }
+        ChatMessage {
+            role: "user".to_string(),
+            content: MessageContent::Parts(parts),
+        }
+    }
+
+    /// The message with each image replaced by an `[image]` note, as kept in the history so that
+    /// images are only sent with the question they came with.
+    pub fn without_images(&self) -> ChatMessage {
+        let MessageContent::Parts(parts) = &self.content else {
+            return self.clone();
+        };
+        let text = parts
+            .iter()
+            .map(|part| match part {
+                ContentPart::Text { text } => text.as_str(),
+                ContentPart::ImageUrl { .. } => "[image]",
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        ChatMessage::new(&self.role, &text)
+    }
+}
@@ -1,30 +1,146 @@
+use std::sync::Arc;
+
 use anyhow::Context;
 use log::info;
-use openai_chatgpt_api::{
-    ChatGpt, ChatGptChatFormat, ChatGptRequestChatCompletions, ChatGptResponse,
-};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::{Config, EmbeddingsConfig, OpenAiConfig, SpeechConfig, TranscriptionConfig};
 
-pub use grammar_checker::check_grammar;
-pub use translation::translate;
+pub use embeddings::embed;
+pub use grammar_checker::{check_grammar, grammar_options};
+pub use message::ChatMessage;
+pub use prompt_tools::{load_prompt_tools, prompt_tool_conversation, run_prompt_tool, PromptTools};
+pub use speech::synthesize_speech;
+pub use summarizer::{parse_link, parse_summary_request, summarize, summary_options};
+pub use transcription::transcribe;
+pub use translation::{translate, translate_options};
 pub use variable_namer::naming_variable;
 
+mod embeddings;
 mod grammar_checker;
+mod message;
+mod prompt_tools;
+mod speech;
+mod summarizer;
+mod transcription;
 mod translation;
 mod variable_namer;
 
+pub type ChatGptRef = Arc<ChatGptClient>;
+
+/// Sends requests to OpenAI, reusing one HTTP client that goes through the configured proxy.
+pub struct ChatGptClient {
+    http: reqwest::Client,
+    /// Fetches the web pages users link to, which may only be on public addresses.
+    pages: reqwest::Client,
+    config: OpenAiConfig,
+    transcription: TranscriptionConfig,
+    speech: SpeechConfig,
+    embeddings: Option<EmbeddingsConfig>,
+}
+
+impl ChatGptClient {
+    pub fn new(config: &Config) -> anyhow::Result<ChatGptClient> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = &config.openai.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy).context("Invalid OpenAI proxy")?);
+        }
+        Ok(ChatGptClient {
+            http: builder
+                .build()
+                .context("Cannot create the OpenAI HTTP client")?,
+            pages: summarizer::page_client(config.commands.summary_proxy.as_deref())?,
+            config: config.openai.clone(),
+            transcription: config.transcription.clone(),
+            speech: config.speech.clone(),
+            embeddings: config.documents.embeddings.clone(),
+        })
+    }
+}
+
+/// An answer from ChatGPT, with the tokens it took to produce it.
+pub struct Completion {
+    pub content: String,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// Sampling settings sent with a request, where set, instead of OpenAI's defaults.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestParameters {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u32>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+}
+
 pub async fn ask_chat_gpt(
-    open_api_token: &str,
-    conversation_history: Vec<ChatGptChatFormat>,
-) -> anyhow::Result<String> {
-    let chat_gpt = ChatGpt::new(open_api_token);
+    chat_gpt: &ChatGptClient,
+    conversation_history: Vec<ChatMessage>,
+) -> anyhow::Result<Completion> {
+    ask_chat_gpt_with(
+        chat_gpt,
+        conversation_history,
+        None,
+        &RequestParameters::default(),
+    )
+    .await
+}
 
-    let request = ChatGptRequestChatCompletions::new("gpt-4", conversation_history);
+/// Asks with `model` instead of the configured one, when given, and with `parameters`.
+pub async fn ask_chat_gpt_with(
+    chat_gpt: &ChatGptClient,
+    conversation_history: Vec<ChatMessage>,
+    model: Option<&str>,
+    parameters: &RequestParameters,
+) -> anyhow::Result<Completion> {
+    let model = model.unwrap_or(&chat_gpt.config.model);
+    let mut request = json!({
+        "model": model,
+        "messages": conversation_history,
+    });
+    let parameters = [
+        ("temperature", parameters.temperature.map(Value::from)),
+        ("top_p", parameters.top_p.map(Value::from)),
+        ("max_tokens", parameters.max_tokens.map(Value::from)),
+        (
+            "presence_penalty",
+            parameters.presence_penalty.map(Value::from),
+        ),
+        (
+            "frequency_penalty",
+            parameters.frequency_penalty.map(Value::from),
+        ),
+    ];
+    for (name, value) in parameters {
+        if let Some(value) = value {
+            request[name] = value;
+        }
+    }
 
-    let res = chat_gpt
-        .chat_completions(&request)
+    let response = chat_gpt
+        .http
+        .post(chat_gpt.config.chat_completions_url()?)
+        .bearer_auth(&chat_gpt.config.api_key)
+        .json(&request)
+        .send()
         .await
-        .map_err(|e| anyhow::anyhow!(e.to_string()))?;
-    let choices = res.to_value().get("choices").context("No choices")?;
+        .context("Cannot reach OpenAI")?;
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .context("Cannot read OpenAI response")?;
+    if !status.is_success() {
+        anyhow::bail!("OpenAI request failed with {status}: {body}");
+    }
+    let res: Value = serde_json::from_str(&body).context("Invalid OpenAI response")?;
+
+    let choices = res.get("choices").context("No choices")?;
     let first_choice = choices.get(0).context("No first choice")?;
     let message = first_choice.get("message").context("No message")?;
     let content = message
@@ -32,26 +148,18 @@ pub async fn ask_chat_gpt(
         .and_then(|e| e.as_str())
         .context("No content")?;
     info!("ChatGPT response: {}", content);
-    Ok(content.to_string())
-}
-
-fn split_options_and_body(
-    user_input: String,
-    default: String,
-    option_flag: char,
-) -> (String, String) {
-    let parts = user_input.splitn(2, ' ').collect::<Vec<&str>>();
-    if parts.len() == 1 {
-        return (default, parts[0].to_string());
-    }
-
-    let lang = parts[0];
-    if let Some(lang) = lang.strip_prefix(&format!("-{option_flag}")) {
-        if lang.is_empty() {
-            return (default, parts[1].to_string());
-        }
-        return (lang.to_string(), parts[1].to_string());
-    }
 
-    (default, user_input)
+    let usage = res.get("usage");
+    let tokens = |name: &str| {
+        usage
+            .and_then(|usage| usage.get(name))
+            .and_then(|tokens| tokens.as_u64())
+            .unwrap_or_default()
+    };
+    Ok(Completion {
+        content: content.to_string(),
+        model: model.to_string(),
+        prompt_tokens: tokens("prompt_tokens"),
+        completion_tokens: tokens("completion_tokens"),
+    })
 }
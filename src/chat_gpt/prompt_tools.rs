@@ -0,0 +1,271 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::chat_gpt::{
+    ask_chat_gpt_with, ChatGptClient, ChatMessage, Completion, RequestParameters,
+};
+use crate::utils::options::{parse_args, OptionSpec, UsageError};
+use crate::utils::template;
+use crate::utils::template::Variables;
+
+/// Telegram allows no more commands than this in a bot's menu.
+const MAX_BOT_COMMANDS: usize = 100;
+const MAX_COMMAND_NAME_CHARS: usize = 32;
+const MAX_COMMAND_DESCRIPTION_CHARS: usize = 256;
+
+/// Prompt tools by command name.
+pub type PromptTools = BTreeMap<String, PromptTool>;
+
+/// A command defined in the tools file, which asks ChatGPT with its own system prompt.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PromptTool {
+    pub description: String,
+    /// System prompt template, where `{{name}}` is replaced with the option's value. Flags are
+    /// `true` or `false`.
+    pub system: String,
+    #[serde(default)]
+    pub options: Vec<ToolOption>,
+    /// Model to ask instead of `openai.model`.
+    pub model: Option<String>,
+    #[serde(default)]
+    pub parameters: RequestParameters,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ToolOption {
+    pub name: String,
+    pub short: Option<char>,
+    /// Whether the option is a switch that takes no value.
+    #[serde(default)]
+    pub flag: bool,
+    pub default: Option<String>,
+    #[serde(default)]
+    pub description: String,
+}
+
+impl PromptTool {
+    pub fn options(&self) -> Vec<OptionSpec> {
+        self.options
+            .iter()
+            .map(|option| {
+                let spec = match option.flag {
+                    true => OptionSpec::flag(&option.name, option.short, &option.description),
+                    false => OptionSpec::value(&option.name, option.short, &option.description),
+                };
+                match &option.default {
+                    Some(default) => spec.with_default(default),
+                    None => spec,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reads the tools file, checking that no tool takes one of the `reserved` command names.
+pub fn load_prompt_tools(path: &Path, reserved: &[String]) -> anyhow::Result<PromptTools> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read prompt tools file {path:?}"))?;
+    let tools: PromptTools = serde_yaml::from_str(&contents)
+        .with_context(|| format!("Invalid prompt tools file {path:?}"))?;
+    validate_prompt_tools(&tools, reserved)
+        .with_context(|| format!("Invalid prompt tools file {path:?}"))?;
+    Ok(tools)
+}
+
+fn validate_prompt_tools(tools: &PromptTools, reserved: &[String]) -> anyhow::Result<()> {
+    if reserved.len() + tools.len() > MAX_BOT_COMMANDS {
+        anyhow::bail!(
+            "There are {} prompt tools, at most {} fit next to the built-in commands",
+            tools.len(),
+            MAX_BOT_COMMANDS - reserved.len().min(MAX_BOT_COMMANDS)
+        );
+    }
+    for (name, tool) in tools {
+        let valid_name = !name.is_empty()
+            && name.chars().count() <= MAX_COMMAND_NAME_CHARS
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid_name {
+            anyhow::bail!(
+                "Prompt tool name '{name}' must be 1 to {MAX_COMMAND_NAME_CHARS} lowercase letters, digits or underscores"
+            );
+        }
+        if reserved.contains(name) {
+            anyhow::bail!("Prompt tool /{name} has the name of a built-in command");
+        }
+        let description_chars = tool.description.trim().chars().count();
+        if !(1..=MAX_COMMAND_DESCRIPTION_CHARS).contains(&description_chars) {
+            anyhow::bail!(
+                "Prompt tool /{name} needs a description of 1 to {MAX_COMMAND_DESCRIPTION_CHARS} characters"
+            );
+        }
+        if tool.system.trim().is_empty() {
+            anyhow::bail!("Prompt tool /{name} has an empty system prompt");
+        }
+        if tool
+            .model
+            .as_ref()
+            .is_some_and(|model| model.trim().is_empty())
+        {
+            anyhow::bail!("Prompt tool /{name} has an empty model");
+        }
+
+        let mut names = HashSet::new();
+        let mut shorts = HashSet::new();
+        for option in &tool.options {
+            let valid_name = !option.name.is_empty()
+                && !option.name.starts_with('-')
+                && !option
+                    .name
+                    .contains(|c: char| c.is_whitespace() || c == '=');
+            if !valid_name {
+                anyhow::bail!(
+                    "Prompt tool /{name} has an option named '{}', names can't contain spaces or '=' or start with '-'",
+                    option.name
+                );
+            }
+            if !names.insert(option.name.as_str()) {
+                anyhow::bail!("Prompt tool /{name} has two --{} options", option.name);
+            }
+            if let Some(short) = option.short {
+                if !short.is_alphabetic() {
+                    anyhow::bail!(
+                        "Prompt tool /{name} has a short option -{short} that isn't a letter"
+                    );
+                }
+                if !shorts.insert(short) {
+                    anyhow::bail!("Prompt tool /{name} has two -{short} options");
+                }
+            }
+            if option.flag && option.default.is_some() {
+                anyhow::bail!(
+                    "Prompt tool /{name} gives the flag --{} a default",
+                    option.name
+                );
+            }
+        }
+        for placeholder in template::placeholders(&tool.system) {
+            if !names.contains(placeholder.as_str()) {
+                anyhow::bail!(
+                    "Prompt tool /{name} uses {{{{{placeholder}}}}} in its prompt, which isn't one of its options"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The conversation asking ChatGPT about the text after the tool's options, with the tool's
+/// prompt filled in with the options' values.
+pub fn prompt_tool_conversation(
+    tool: &PromptTool,
+    user_input: &str,
+) -> Result<Vec<ChatMessage>, UsageError> {
+    let options = tool.options();
+    let args = parse_args(&options, user_input)?;
+    if args.body.is_empty() {
+        return Err(UsageError("Nothing to work on".to_string()));
+    }
+    let variables: Variables = options
+        .iter()
+        .map(|option| {
+            let value = match option.takes_value {
+                true => args.value(&option.name).unwrap_or_default().to_string(),
+                false => args.flag(&option.name).to_string(),
+            };
+            (option.name.clone(), value)
+        })
+        .collect();
+    Ok(vec![
+        ChatMessage::new_system(&template::render(&tool.system, &variables)),
+        ChatMessage::new_user(&args.body),
+    ])
+}
+
+/// Asks ChatGPT with the tool's model and parameters.
+pub async fn run_prompt_tool(
+    chat_gpt: &ChatGptClient,
+    tool: &PromptTool,
+    conversation: Vec<ChatMessage>,
+) -> anyhow::Result<Completion> {
+    ask_chat_gpt_with(
+        chat_gpt,
+        conversation,
+        tool.model.as_deref(),
+        &tool.parameters,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(yaml: &str) -> anyhow::Result<()> {
+        let tools: PromptTools = serde_yaml::from_str(yaml).unwrap();
+        validate_prompt_tools(&tools, &["help".to_string(), "trans".to_string()])
+    }
+
+    #[test]
+    fn accepts_valid_tools() {
+        validate(
+            "commit:
+  description: Write a commit message
+  system: 'Write it in {{lang}}. Use Conventional Commits: {{conventional}}'
+  options:
+    - { name: lang, short: l, default: English }
+    - { name: conventional, short: c, flag: true }
+",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_reserved_names() {
+        let e = validate("trans:\n  description: Translate\n  system: Translate.\n").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Prompt tool /trans has the name of a built-in command"
+        );
+    }
+
+    #[test]
+    fn rejects_placeholders_that_are_not_options() {
+        let e = validate(
+            "commit:
+  description: Write a commit message
+  system: 'Write it in {{language}}.'
+  options:
+    - { name: lang }
+",
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Prompt tool /commit uses {{language}} in its prompt, which isn't one of its options"
+        );
+    }
+
+    #[test]
+    fn rejects_flags_with_a_default() {
+        let e = validate(
+            "commit:
+  description: Write a commit message
+  system: Write a commit message.
+  options:
+    - { name: conventional, flag: true, default: 'true' }
+",
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Prompt tool /commit gives the flag --conventional a default"
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::Context;
+use serde_json::json;
+
+use crate::chat_gpt::ChatGptClient;
+use crate::storages::Speech;
+
+/// Longest input the speech endpoint accepts, longer text is cut off.
+const MAX_INPUT_CHARS: usize = 4096;
+
+/// Reads `text` out through the configured `/audio/speech` compatible endpoint, returning
+/// OGG/Opus audio as Telegram voice messages expect.
+pub async fn synthesize_speech(
+    chat_gpt: &ChatGptClient,
+    text: &str,
+    speech: &Speech,
+) -> anyhow::Result<Vec<u8>> {
+    let config = &chat_gpt.speech;
+    let api_key = config.api_key.as_ref().unwrap_or(&chat_gpt.config.api_key);
+    let input = text.chars().take(MAX_INPUT_CHARS).collect::<String>();
+    let request = json!({
+        "model": config.model,
+        "input": input,
+        "voice": speech.voice.as_ref().unwrap_or(&config.voice),
+        "speed": speech.speed.unwrap_or(config.speed),
+        "response_format": "opus",
+    });
+
+    let response = chat_gpt
+        .http
+        .post(config.url.clone())
+        .bearer_auth(api_key)
+        .json(&request)
+        .send()
+        .await
+        .context("Cannot reach the speech service")?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("Speech request failed with {status}: {body}");
+    }
+    let audio = response
+        .bytes()
+        .await
+        .context("Cannot read speech response")?;
+    Ok(audio.to_vec())
+}
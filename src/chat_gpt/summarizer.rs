@@ -0,0 +1,484 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use hyper::client::connect::dns::Name;
+use log::warn;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect;
+use scraper::{ElementRef, Html, Node, Selector};
+use url::{Host, Url};
+
+use crate::chat_gpt::{ask_chat_gpt, ChatGptClient, ChatMessage, Completion};
+use crate::utils::options::{parse_args, OptionSpec, UsageError};
+
+/// Pages larger than this are cut off while downloading.
+const MAX_PAGE_BYTES: usize = 5 * 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 5;
+/// An `article` or `main` element with less text than this isn't taken as the page's content.
+const MIN_CONTENT_CHARS: usize = 200;
+
+/// Elements that hold navigation, scripts or forms rather than the page's content.
+const SKIPPED_ELEMENTS: &[&str] = &[
+    "script", "style", "noscript", "template", "svg", "iframe", "nav", "header", "footer", "aside",
+    "form", "button", "select",
+];
+const BLOCK_ELEMENTS: &[&str] = &[
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "br",
+    "li",
+    "ul",
+    "ol",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "pre",
+    "blockquote",
+    "table",
+    "tr",
+    "figcaption",
+    "dd",
+    "dt",
+];
+
+/// The options `/summarize` takes.
+pub fn summary_options() -> Vec<OptionSpec> {
+    vec![OptionSpec::value(
+        "lang",
+        Some('l'),
+        "Language to summarize in, the content's own when not given",
+    )]
+}
+
+/// What `/summarize` was asked to summarize, and how.
+pub struct SummaryRequest {
+    /// A web page's URL, or the text itself.
+    content: String,
+    lang: Option<String>,
+}
+
+pub fn parse_summary_request(user_input: &str) -> Result<SummaryRequest, UsageError> {
+    let args = parse_args(&summary_options(), user_input)?;
+    if args.body.is_empty() {
+        return Err(UsageError("Nothing to summarize".to_string()));
+    }
+    Ok(SummaryRequest {
+        lang: args.value("lang").map(str::to_string),
+        content: args.body,
+    })
+}
+
+/// Summarizes the request's web page or text, keeping what is sent to ChatGPT within roughly
+/// `max_tokens`.
+pub async fn summarize(
+    chat_gpt: &ChatGptClient,
+    max_tokens: usize,
+    request: SummaryRequest,
+) -> anyhow::Result<Completion> {
+    let text = match parse_link(&request.content) {
+        Some(url) => {
+            let page = fetch_page(chat_gpt, &url).await?;
+            format!("URL: {url}\n\n{page}")
+        }
+        None => request.content,
+    };
+    let text = trim_to_tokens(&text, max_tokens);
+    let lang = match request.lang {
+        Some(lang) => format!("in {lang}"),
+        None => "in the language it is written in".to_string(),
+    };
+    let conversation_history: Vec<ChatMessage> = vec![
+        ChatMessage::new_system(&format!(
+            "Summarize the content the user sends, {lang}. \
+            Start with a one-sentence TL;DR, then list the key points as short bullets, \
+            and end with any conclusions, numbers or caveats worth remembering. \
+            Don't add anything that isn't in the content."
+        )),
+        ChatMessage::new_user(&text),
+    ];
+
+    ask_chat_gpt(chat_gpt, conversation_history).await
+}
+
+/// The http(s) URL `text` consists of, if it is nothing but a link.
+pub fn parse_link(text: &str) -> Option<Url> {
+    let text = text.trim();
+    if text.contains(char::is_whitespace) {
+        return None;
+    }
+    Url::parse(text)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https") && url.host().is_some())
+}
+
+/// The client pages are fetched with. It connects only to public addresses, checked when a host
+/// is resolved and on every redirect, so that links can't reach the bot's own network.
+///
+/// Through a proxy, hosts are resolved by the proxy instead, so only links and redirects to IP
+/// addresses can be checked.
+pub(super) fn page_client(proxy: Option<&str>) -> anyhow::Result<reqwest::Client> {
+    let redirects = redirect::Policy::custom(|attempt| {
+        // The previous URLs include the one first asked for.
+        match check_redirect(attempt.url(), attempt.previous().len()) {
+            Ok(()) => attempt.follow(),
+            Err(e) => attempt.error(e),
+        }
+    });
+    let builder = reqwest::Client::builder()
+        .redirect(redirects)
+        .timeout(FETCH_TIMEOUT);
+    let builder = match proxy {
+        Some(proxy) => {
+            warn!(
+                "Web pages are fetched through commands.summary_proxy, which resolves host names \
+                itself: only links to IP addresses are checked to be public"
+            );
+            builder.proxy(reqwest::Proxy::all(proxy).context("Invalid summary proxy")?)
+        }
+        // Without this, reqwest would pick up HTTP_PROXY and friends, bypassing the resolver.
+        None => builder.no_proxy().dns_resolver(Arc::new(PublicResolver)),
+    };
+    builder
+        .build()
+        .context("Cannot create the HTTP client for web pages")
+}
+
+/// Resolves hosts to their public addresses only, failing when they have none.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Checks the URL a page redirects to, after `redirects` redirects were already followed.
+fn check_redirect(url: &Url, redirects: usize) -> Result<(), String> {
+    if redirects > MAX_REDIRECTS {
+        return Err("Too many redirects".to_string());
+    }
+    check_public_url(url)
+}
+
+/// Checks hosts given as IP addresses, which are connected to without being resolved.
+fn check_public_url(url: &Url) -> Result<(), String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("{url} is not an http(s) URL"));
+    }
+    let ip = match url.host() {
+        Some(Host::Domain(_)) => return Ok(()),
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        None => return Err(format!("{url} has no host")),
+    };
+    match is_public(ip) {
+        true => Ok(()),
+        false => Err(format!("{ip} is not a public address")),
+    }
+}
+
+/// Whether `ip` is reachable on the internet, rather than on the host itself, a private or
+/// provider network, or a range reserved for special use.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                // 0.0.0.0/8, "this network".
+                || a == 0
+                // 100.64.0.0/10, carrier-grade NAT, also used inside some clouds.
+                || (a == 100 && b & 0xc0 == 64)
+                // 192.0.0.0/24, IETF protocol assignments.
+                || (a == 192 && b == 0 && c == 0)
+                // 198.18.0.0/15, benchmarking.
+                || (a == 198 && b & 0xfe == 18)
+                // 240.0.0.0/4, reserved, including the broadcast address.
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                !(ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.is_multicast()
+                    // ::/96, IPv4-compatible addresses, including :: and ::1.
+                    || segments[..6] == [0; 6]
+                    // 64:ff9b::/96, NAT64, which reaches IPv4 addresses through a gateway.
+                    || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+                    // fec0::/10, deprecated site-local addresses.
+                    || segments[0] & 0xffc0 == 0xfec0)
+            }
+        },
+    }
+}
+
+async fn fetch_page(chat_gpt: &ChatGptClient, url: &Url) -> anyhow::Result<String> {
+    check_public_url(url).map_err(anyhow::Error::msg)?;
+    let mut response = chat_gpt
+        .pages
+        .get(url.clone())
+        .send()
+        .await
+        .with_context(|| format!("Cannot fetch {url}"))?
+        .error_for_status()
+        .with_context(|| format!("Cannot fetch {url}"))?;
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("text/html")
+        .to_lowercase();
+    let is_html = content_type.contains("html");
+    if !is_html && !content_type.starts_with("text/") {
+        anyhow::bail!("{url} is not a web page ({content_type})");
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_PAGE_BYTES {
+            body.truncate(MAX_PAGE_BYTES);
+            break;
+        }
+    }
+    let body = String::from_utf8_lossy(&body);
+    let text = if is_html {
+        readable_text(&body)
+    } else {
+        body.into_owned()
+    };
+    if text.trim().is_empty() {
+        anyhow::bail!("No text found on {url}");
+    }
+    Ok(text)
+}
+
+/// The title and main text of an HTML page. The content is taken from the `article` or `main`
+/// element with the most text, falling back to the body, without navigation, scripts and forms.
+fn readable_text(html: &str) -> String {
+    let document = Html::parse_document(html);
+    let title_selector = Selector::parse("title").unwrap();
+    let content_selector = Selector::parse("article, main, [role=main]").unwrap();
+    let body_selector = Selector::parse("body").unwrap();
+
+    let title = document
+        .select(&title_selector)
+        .next()
+        .map(|title| collapse_whitespace(&title.text().collect::<String>()))
+        .unwrap_or_default();
+    let element_text = |element: ElementRef| {
+        let mut text = String::new();
+        collect_text(element, &mut text);
+        tidy_lines(&text)
+    };
+    let mut content = document
+        .select(&content_selector)
+        .map(element_text)
+        .max_by_key(|text| text.len())
+        .unwrap_or_default();
+    // Pages whose article element holds only a teaser are read as a whole.
+    if content.chars().count() < MIN_CONTENT_CHARS {
+        if let Some(body) = document.select(&body_selector).next() {
+            content = element_text(body);
+        }
+    }
+
+    match title.is_empty() {
+        true => content,
+        false => format!("{title}\n\n{content}"),
+    }
+}
+
+fn collect_text(element: ElementRef, text: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(content) => text.push_str(content),
+            Node::Element(child_element) => {
+                let name = child_element.name();
+                if SKIPPED_ELEMENTS.contains(&name) {
+                    continue;
+                }
+                let is_block = BLOCK_ELEMENTS.contains(&name);
+                if is_block {
+                    text.push('\n');
+                }
+                if let Some(child) = ElementRef::wrap(child) {
+                    collect_text(child, text);
+                }
+                if is_block {
+                    text.push('\n');
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// One line per paragraph, with runs of spaces and blank lines removed.
+fn tidy_lines(text: &str) -> String {
+    text.lines()
+        .map(collapse_whitespace)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// A rough token count: about four characters per token for ASCII text, one per character for
+/// other scripts.
+fn estimate_tokens(c: char) -> f64 {
+    match c.is_ascii() {
+        true => 0.25,
+        false => 1.0,
+    }
+}
+
+fn trim_to_tokens(text: &str, max_tokens: usize) -> String {
+    let mut tokens = 0.0;
+    for (index, c) in text.char_indices() {
+        tokens += estimate_tokens(c);
+        if tokens > max_tokens as f64 {
+            return format!("{}\n[…]", &text[..index]);
+        }
+    }
+    text.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn accepts_public_addresses() {
+        for ip in [
+            "1.1.1.1",
+            "8.8.8.8",
+            "100.63.255.255",
+            "100.128.0.1",
+            "192.0.1.1",
+            "198.17.255.255",
+            "198.20.0.1",
+            "223.255.255.255",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+            "64:ff9c::1",
+        ] {
+            assert!(public(ip), "{ip} should be public");
+        }
+    }
+
+    #[test]
+    fn rejects_non_public_ipv4_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "100.127.255.254",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!public(ip), "{ip} should not be public");
+        }
+    }
+
+    #[test]
+    fn rejects_non_public_ipv6_addresses() {
+        for ip in [
+            "::",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "::7f00:1",
+            "::a00:1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::808:808",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "fec0::1",
+            "feff::1",
+            "ff02::1",
+        ] {
+            assert!(!public(ip), "{ip} should not be public");
+        }
+    }
+
+    #[test]
+    fn checks_urls_with_ip_hosts() {
+        let check = |url: &str| check_public_url(&Url::parse(url).unwrap());
+        assert!(check("https://example.com/page").is_ok());
+        assert!(check("http://8.8.8.8/").is_ok());
+        assert!(check("http://[2606:4700::1111]/").is_ok());
+        assert!(check("http://127.0.0.1:8080/").is_err());
+        assert!(check("http://[::1]/").is_err());
+        assert!(check("http://[::ffff:192.168.0.1]/").is_err());
+        // Other spellings of IPv4 addresses are normalized by the URL parser.
+        assert!(check("http://0x7f.1/").is_err());
+        assert!(check("http://2130706433/").is_err());
+        assert!(check("ftp://example.com/").is_err());
+        assert!(check("file:///etc/passwd").is_err());
+    }
+
+    #[test]
+    fn checks_redirects() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        assert!(check_redirect(&url("https://example.com/next"), 1).is_ok());
+        assert!(check_redirect(&url("https://example.com/next"), MAX_REDIRECTS).is_ok());
+        assert_eq!(
+            check_redirect(&url("https://example.com/next"), MAX_REDIRECTS + 1),
+            Err("Too many redirects".to_string())
+        );
+        assert!(check_redirect(&url("http://169.254.169.254/latest/meta-data/"), 1).is_err());
+        assert!(check_redirect(&url("http://10.0.0.1/"), 1).is_err());
+        assert!(check_redirect(&url("gopher://example.com/"), 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn resolves_only_public_addresses() {
+        let name = |host: &str| host.parse::<Name>().unwrap();
+        let error = PublicResolver
+            .resolve(name("localhost"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "localhost has no public address");
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::Context;
+use log::info;
+use reqwest::multipart::{Form, Part};
+use serde_json::Value;
+
+use crate::chat_gpt::ChatGptClient;
+
+/// Turns speech into text through the configured `/audio/transcriptions` compatible endpoint.
+/// `file_name` tells the service the audio format.
+pub async fn transcribe(
+    chat_gpt: &ChatGptClient,
+    audio: Vec<u8>,
+    file_name: String,
+) -> anyhow::Result<String> {
+    let config = &chat_gpt.transcription;
+    let api_key = config.api_key.as_ref().unwrap_or(&chat_gpt.config.api_key);
+    let mut form = Form::new()
+        .text("model", config.model.clone())
+        .part("file", Part::bytes(audio).file_name(file_name));
+    if let Some(language) = &config.language {
+        form = form.text("language", language.clone());
+    }
+
+    let response = chat_gpt
+        .http
+        .post(config.url.clone())
+        .bearer_auth(api_key)
+        .multipart(form)
+        .send()
+        .await
+        .context("Cannot reach the transcription service")?;
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .context("Cannot read transcription response")?;
+    if !status.is_success() {
+        anyhow::bail!("Transcription request failed with {status}: {body}");
+    }
+    let res: Value = serde_json::from_str(&body).context("Invalid transcription response")?;
+    let text = res
+        .get("text")
+        .and_then(|text| text.as_str())
+        .context("No text")?
+        .trim();
+    info!("Transcribed {} characters", text.chars().count());
+    Ok(text.to_string())
+}
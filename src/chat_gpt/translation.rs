@@ -1,31 +1,27 @@
-use openai_chatgpt_api::ChatGptChatFormat;
+use crate::chat_gpt::ChatMessage;
+use crate::utils::options::{parse_args, OptionSpec, UsageError};
 
-use crate::chat_gpt::ask_chat_gpt;
-
-pub async fn translate(open_api_token: &str, user_input: String) -> anyhow::Result<String> {
-    let (lang, text) = get_lang_and_text(user_input);
-    let conversation_history: Vec<ChatGptChatFormat> = vec![
-        ChatGptChatFormat::new_system(&format!("translate input text to {lang}")),
-        ChatGptChatFormat::new_user(&text),
-    ];
-
-    ask_chat_gpt(open_api_token, conversation_history).await
+/// The options `/trans` takes.
+pub fn translate_options(default_lang: &str) -> Vec<OptionSpec> {
+    vec![
+        OptionSpec::value("lang", Some('l'), "Language to translate to").with_default(default_lang),
+        OptionSpec::flag("formal", Some('f'), "Use a formal register"),
+    ]
 }
 
-fn get_lang_and_text(user_input: String) -> (String, String) {
-    let default_lang = "english".to_string();
-    let parts = user_input.splitn(2, ' ').collect::<Vec<&str>>();
-    if parts.len() == 1 {
-        return (default_lang, parts[0].to_string());
+/// The conversation asking ChatGPT to translate the text after the options.
+pub fn translate(default_lang: &str, user_input: &str) -> Result<Vec<ChatMessage>, UsageError> {
+    let args = parse_args(&translate_options(default_lang), user_input)?;
+    if args.body.is_empty() {
+        return Err(UsageError("Nothing to translate".to_string()));
     }
-
-    let lang = parts[0];
-    if let Some(lang) = lang.strip_prefix("-l") {
-        if lang.is_empty() {
-            return (default_lang, parts[1].to_string());
-        }
-        return (lang.to_string(), parts[1].to_string());
+    let lang = args.value("lang").unwrap_or(default_lang);
+    let mut system = format!("translate input text to {lang}");
+    if args.flag("formal") {
+        system.push_str(", using a formal register");
     }
-
-    (default_lang, user_input)
+    Ok(vec![
+        ChatMessage::new_system(&system),
+        ChatMessage::new_user(&args.body),
+    ])
 }
@@ -1,14 +1,17 @@
-use openai_chatgpt_api::ChatGptChatFormat;
+use crate::chat_gpt::ChatMessage;
+use crate::utils::options::{parse_args, UsageError};
 
-use crate::chat_gpt::ask_chat_gpt;
-
-pub async fn naming_variable(open_api_token: &str, scene: String) -> anyhow::Result<String> {
-    let conversation_history: Vec<ChatGptChatFormat> = vec![
-        ChatGptChatFormat::new_system(
+/// The conversation asking ChatGPT to name what `scene` describes.
+pub fn naming_variable(scene: &str) -> Result<Vec<ChatMessage>, UsageError> {
+    // No options, but `--` still lets a scene start with a dash.
+    let args = parse_args(&[], scene)?;
+    if args.body.is_empty() {
+        return Err(UsageError("Describe what to name".to_string()));
+    }
+    Ok(vec![
+        ChatMessage::new_system(
             "Just give a variable name or method name based on the scene I ask you",
         ),
-        ChatGptChatFormat::new_user(&scene),
-    ];
-
-    ask_chat_gpt(open_api_token, conversation_history).await
+        ChatMessage::new_user(&args.body),
+    ])
 }
@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::chat_gpt;
+use crate::config::Config;
+use crate::storages;
+use crate::storages::{Backend, FileFormat, Role, StorageRef, Usage};
+use crate::telegram;
+
+#[derive(Parser)]
+#[command(version, about = "A ChatGPT bot for Telegram")]
+pub struct Cli {
+    /// Config file, `config.toml` or `config.yaml` in the working directory by default
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Directory of roles, sessions and usage, overriding the config
+    #[arg(long, global = true)]
+    pub storage_dir: Option<PathBuf>,
+    /// ChatGPT model, overriding the config
+    #[arg(long, global = true)]
+    pub model: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the bot (the default), through a webhook when `webhook.url` is configured and by
+    /// polling otherwise
+    Run {
+        /// Require webhook mode, failing when it isn't configured
+        #[arg(long)]
+        webhook: bool,
+    },
+    /// Manage roles
+    #[command(subcommand)]
+    Roles(RolesCommand),
+    /// Inspect chat sessions
+    #[command(subcommand)]
+    Sessions(SessionsCommand),
+    /// Inspect token usage
+    #[command(subcommand)]
+    Usage(UsageCommand),
+    /// Check the configuration and exit
+    CheckConfig,
+    /// Copy all data from one storage backend to another
+    Migrate { from: Backend, to: Backend },
+}
+
+#[derive(Subcommand)]
+pub enum RolesCommand {
+    /// List all roles
+    List,
+    /// Add a role
+    Add {
+        name: String,
+        /// The role's system prompt
+        system: String,
+        #[arg(long)]
+        description: Option<String>,
+        #[arg(long)]
+        icon: Option<String>,
+    },
+    /// Remove a role
+    Remove { name: String },
+    /// Export all roles
+    Export {
+        #[arg(long, default_value = "yaml")]
+        format: FileFormat,
+        /// File to write to, standard output by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SessionsCommand {
+    /// Export the session of a chat
+    #[command(allow_negative_numbers = true)]
+    Export {
+        chat_id: i64,
+        #[arg(long, default_value = "yaml")]
+        format: FileFormat,
+        /// File to write to, standard output by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UsageCommand {
+    /// Report requests and tokens
+    Report {
+        #[arg(long, value_enum, default_value_t = GroupBy::User)]
+        by: GroupBy,
+        /// First day to include, as YYYY-MM-DD
+        #[arg(long)]
+        since: Option<NaiveDate>,
+        /// Last day to include, as YYYY-MM-DD
+        #[arg(long)]
+        until: Option<NaiveDate>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum GroupBy {
+    Date,
+    User,
+    Model,
+}
+
+pub fn check_config(config: &Config) -> Result<(), anyhow::Error> {
+    config.validate()?;
+    println!("Configuration is valid.");
+    println!("Model: {}", config.openai.model);
+    println!(
+        "Storage: {:?} in {}",
+        config.storage.backend,
+        config.storage.dir.display()
+    );
+    println!("Dialogues: {}", config.storage.dialogues);
+    match config.transcription.enabled {
+        true => println!("Transcription: {}", config.transcription.url),
+        false => println!("Transcription: off"),
+    }
+    if let Some(path) = &config.commands.tools_file {
+        let tools = chat_gpt::load_prompt_tools(path, &telegram::builtin_command_names())?;
+        println!("Prompt tools: {} from {}", tools.len(), path.display());
+    }
+    match &config.webhook.url {
+        Some(url) => println!(
+            "Updates: webhook {url}, listening on {}",
+            config.webhook.listen
+        ),
+        None => println!("Updates: polling"),
+    }
+    Ok(())
+}
+
+pub fn roles(storage: StorageRef, command: RolesCommand) -> Result<(), anyhow::Error> {
+    let mut roles = storage.load_roles()?;
+    match command {
+        RolesCommand::List => {
+            let mut roles = roles.iter().collect::<Vec<_>>();
+            roles.sort_by_key(|(name, _)| *name);
+            for (name, role) in roles {
+                println!("{}: {}", role.display_name(name), role.summary());
+            }
+        }
+        RolesCommand::Add {
+            name,
+            system,
+            description,
+            icon,
+        } => {
+            if roles.contains_key(&name) {
+                anyhow::bail!("Role {name} already exists");
+            }
+            roles.insert(
+                name.clone(),
+                Role {
+                    description,
+                    icon,
+                    ..Role::new(system)
+                },
+            );
+            storages::validate_roles(&roles)?;
+            storage.save_roles(&roles)?;
+            println!("Role {name} added.");
+        }
+        RolesCommand::Remove { name } => {
+            if roles.remove(&name).is_none() {
+                anyhow::bail!("Role {name} not found");
+            }
+            storage.save_roles(&roles)?;
+            println!("Role {name} removed.");
+        }
+        RolesCommand::Export { format, output } => {
+            write_output(
+                &storages::serialize_roles(&roles, format)?,
+                output.as_deref(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn sessions(storage: StorageRef, command: SessionsCommand) -> Result<(), anyhow::Error> {
+    match command {
+        SessionsCommand::Export {
+            chat_id,
+            format,
+            output,
+        } => {
+            let sessions = storage.load_sessions()?;
+            let session = sessions
+                .get(&chat_id)
+                .with_context(|| format!("No session for chat {chat_id}"))?;
+            write_output(&storages::serialize(session, format)?, output.as_deref())?;
+        }
+    }
+    Ok(())
+}
+
+pub fn usage(storage: StorageRef, command: UsageCommand) -> Result<(), anyhow::Error> {
+    match command {
+        UsageCommand::Report { by, since, until } => {
+            let since = since.map(|date| date.to_string());
+            let until = until.map(|date| date.to_string());
+            let mut rows: BTreeMap<String, Usage> = BTreeMap::new();
+            let mut total = Usage::default();
+            for usage in storage.load_usage()? {
+                if since.as_ref().is_some_and(|since| usage.date < *since)
+                    || until.as_ref().is_some_and(|until| usage.date > *until)
+                {
+                    continue;
+                }
+                let key = match by {
+                    GroupBy::Date => usage.date.clone(),
+                    GroupBy::User => usage.user_id.to_string(),
+                    GroupBy::Model => usage.model.clone(),
+                };
+                rows.entry(key).or_default().add(&usage);
+                total.add(&usage);
+            }
+
+            let heading = match by {
+                GroupBy::Date => "DATE",
+                GroupBy::User => "USER",
+                GroupBy::Model => "MODEL",
+            };
+            println!(
+                "{heading:<24} {:>10} {:>14} {:>14} {:>14}",
+                "REQUESTS", "PROMPT", "COMPLETION", "TOTAL"
+            );
+            let total_key = "total".to_string();
+            for (key, usage) in rows.iter().chain(std::iter::once((&total_key, &total))) {
+                println!(
+                    "{key:<24} {:>10} {:>14} {:>14} {:>14}",
+                    usage.requests,
+                    usage.prompt_tokens,
+                    usage.completion_tokens,
+                    usage.prompt_tokens + usage.completion_tokens
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_output(contents: &str, output: Option<&Path>) -> Result<(), anyhow::Error> {
+    match output {
+        Some(path) => {
+            std::fs::write(path, contents).with_context(|| format!("Cannot write {path:?}"))
+        }
+        None => {
+            print!("{contents}");
+            Ok(())
+        }
+    }
+}
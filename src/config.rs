@@ -0,0 +1,571 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use serde::Deserialize;
+use url::Url;
+
+use crate::storages;
+use crate::storages::{Backend, MAX_SPEECH_SPEED, MIN_SPEECH_SPEED};
+
+/// Prefixes of OpenAI models that accept images.
+const VISION_MODEL_PREFIXES: [&str; 9] = [
+    "gpt-4o",
+    "gpt-4-turbo",
+    "gpt-4-vision",
+    "gpt-4.1",
+    "gpt-4.5",
+    "gpt-5",
+    "o1",
+    "o3",
+    "o4",
+];
+
+/// Files looked up in the working directory when no `--config` is given.
+const DEFAULT_CONFIG_FILES: [&str; 3] = ["config.toml", "config.yaml", "config.yml"];
+
+pub type ConfigRef = Arc<Config>;
+
+/// Bot configuration. Values come from the config file, then environment variables, then command
+/// line flags, each overriding the previous one.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub telegram: TelegramConfig,
+    pub webhook: WebhookConfig,
+    pub openai: OpenAiConfig,
+    pub transcription: TranscriptionConfig,
+    pub speech: SpeechConfig,
+    pub documents: DocumentsConfig,
+    pub storage: StorageConfig,
+    pub access: AccessConfig,
+    pub limits: LimitsConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub queue: QueueConfig,
+    pub commands: CommandsConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramConfig {
+    pub token: String,
+    /// HTTP or SOCKS5 proxy for requests to Telegram, e.g. `socks5://127.0.0.1:1080`.
+    pub proxy: Option<String>,
+    /// Chat that receives operational reports, such as roles file reload errors.
+    pub admin_chat_id: Option<i64>,
+}
+
+/// Updates are received through a webhook when `url` is set, and by polling otherwise.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Public URL Telegram sends updates to.
+    pub url: Option<Url>,
+    /// Address the webhook listener binds to.
+    pub listen: SocketAddr,
+    /// Path the listener serves, when a reverse proxy forwards `url` to a different path.
+    /// Defaults to the path of `url`.
+    pub path: Option<String>,
+    /// Token Telegram sends with every update, generated on startup when not set.
+    pub secret_token: Option<String>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            url: None,
+            listen: SocketAddr::from(([0, 0, 0, 0], 8443)),
+            path: None,
+            secret_token: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenAiConfig {
+    pub api_key: String,
+    pub model: String,
+    /// Base URL of the API, for services compatible with OpenAI's chat completions.
+    pub url: Url,
+    /// Whether the model accepts images, guessed from its name when not set.
+    pub vision: Option<bool>,
+    /// HTTP or SOCKS5 proxy for requests to OpenAI.
+    pub proxy: Option<String>,
+}
+
+impl OpenAiConfig {
+    /// The chat completions endpoint under `url`.
+    pub fn chat_completions_url(&self) -> Result<Url, anyhow::Error> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("openai.url {} can't be a base URL", self.url))?
+            .pop_if_empty()
+            .extend(["chat", "completions"]);
+        Ok(url)
+    }
+
+    pub fn supports_images(&self) -> bool {
+        self.vision.unwrap_or_else(|| {
+            VISION_MODEL_PREFIXES
+                .iter()
+                .any(|prefix| self.model.starts_with(prefix))
+        })
+    }
+}
+
+impl Default for OpenAiConfig {
+    fn default() -> Self {
+        OpenAiConfig {
+            api_key: String::new(),
+            model: "gpt-4".to_string(),
+            url: Url::parse("https://api.openai.com/v1").expect("valid default OpenAI URL"),
+            vision: None,
+            proxy: None,
+        }
+    }
+}
+
+/// Speech-to-text for voice and audio messages, through an endpoint compatible with OpenAI's
+/// `/audio/transcriptions`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranscriptionConfig {
+    pub enabled: bool,
+    pub url: Url,
+    pub model: String,
+    /// Key for the endpoint, the OpenAI key by default.
+    pub api_key: Option<String>,
+    /// Language spoken in the messages, as an ISO-639-1 code. Detected when not set.
+    pub language: Option<String>,
+}
+
+impl Default for TranscriptionConfig {
+    fn default() -> Self {
+        TranscriptionConfig {
+            enabled: true,
+            url: Url::parse("https://api.openai.com/v1/audio/transcriptions")
+                .expect("valid default transcription URL"),
+            model: "whisper-1".to_string(),
+            api_key: None,
+            language: None,
+        }
+    }
+}
+
+/// Text-to-speech for voice answers, through an endpoint compatible with OpenAI's
+/// `/audio/speech`. Roles and chats decide whether answers are spoken.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpeechConfig {
+    pub url: Url,
+    pub model: String,
+    /// Key for the endpoint, the OpenAI key by default.
+    pub api_key: Option<String>,
+    /// Voice for roles that don't choose one.
+    pub voice: String,
+    /// Speed for roles that don't choose one, from 0.25 to 4.0.
+    pub speed: f64,
+    /// Send the answer's text along with the voice message, rather than only the voice message.
+    pub with_text: bool,
+}
+
+impl Default for SpeechConfig {
+    fn default() -> Self {
+        SpeechConfig {
+            url: Url::parse("https://api.openai.com/v1/audio/speech")
+                .expect("valid default speech URL"),
+            model: "tts-1".to_string(),
+            api_key: None,
+            voice: "alloy".to_string(),
+            speed: 1.0,
+            with_text: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DocumentsConfig {
+    /// Most characters of uploaded files sent to ChatGPT as a whole with a question, all files
+    /// together. Files that don't fit are added to the chat's documents, and only passages
+    /// relevant to a question are sent.
+    pub max_chars: usize,
+    /// Length of the passages documents are split into, in characters.
+    pub chunk_chars: usize,
+    /// Most passages sent along with a question.
+    pub passages: usize,
+    /// Finds passages by meaning too, not only by the words they share with the question.
+    pub embeddings: Option<EmbeddingsConfig>,
+}
+
+impl Default for DocumentsConfig {
+    fn default() -> Self {
+        DocumentsConfig {
+            max_chars: 20_000,
+            chunk_chars: 1500,
+            passages: 4,
+            embeddings: None,
+        }
+    }
+}
+
+/// An endpoint compatible with OpenAI's `/embeddings`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingsConfig {
+    pub url: Url,
+    pub model: String,
+    /// Key for the endpoint, the OpenAI key by default.
+    pub api_key: Option<String>,
+}
+
+impl Default for EmbeddingsConfig {
+    fn default() -> Self {
+        EmbeddingsConfig {
+            url: Url::parse("https://api.openai.com/v1/embeddings")
+                .expect("valid default embeddings URL"),
+            model: "text-embedding-3-small".to_string(),
+            api_key: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub dir: PathBuf,
+    pub backend: Backend,
+    /// Where dialogue states are kept: `sqlite`, `memory` or a `redis://` URL.
+    pub dialogues: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            dir: PathBuf::from(storages::DEFAULT_STORAGE_DIR),
+            backend: Backend::Yaml,
+            dialogues: "sqlite".to_string(),
+        }
+    }
+}
+
+/// Users and chats allowed to talk to the bot. Everyone is allowed when both lists are empty.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    pub allowed_users: Vec<u64>,
+    pub allowed_chats: Vec<i64>,
+}
+
+impl AccessConfig {
+    pub fn is_allowed(&self, user_id: Option<u64>, chat_id: Option<i64>) -> bool {
+        (self.allowed_users.is_empty() && self.allowed_chats.is_empty())
+            || user_id.is_some_and(|id| self.allowed_users.contains(&id))
+            || chat_id.is_some_and(|id| self.allowed_chats.contains(&id))
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Most recent messages sent to ChatGPT along with the role's system prompt.
+    pub max_history_messages: Option<usize>,
+    /// Longest message, in characters, accepted from a user.
+    pub max_message_length: Option<usize>,
+}
+
+/// Limits on requests to ChatGPT. Requests aren't limited at a level without a limit.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitsConfig {
+    pub user: Option<RateLimit>,
+    pub chat: Option<RateLimit>,
+    pub global: Option<RateLimit>,
+}
+
+/// A token bucket holding up to `burst` requests, refilled with `per_minute` requests a minute.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: f64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// How long to wait for more messages before answering, so that messages sent in quick
+    /// succession are answered together. 0 answers every message right away.
+    pub debounce_ms: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig { debounce_ms: 1000 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandsConfig {
+    /// Language `/trans` translates to when no `-l` is given.
+    pub translate_language: String,
+    /// Language `/gramcheck` explains in when no `-l` is given.
+    pub grammar_language: String,
+    /// Roughly how many tokens of a page or text `/summarize` sends to ChatGPT.
+    pub summary_max_tokens: usize,
+    /// HTTP or SOCKS5 proxy `/summarize` fetches pages through. Pages are fetched directly
+    /// without it, so the addresses hosts resolve to can be checked to be public.
+    pub summary_proxy: Option<String>,
+    /// How long to wait for the user to stop typing before answering an inline query.
+    pub inline_debounce_ms: u64,
+    /// YAML file defining more commands, each with its own prompt. See `tools.example.yaml`.
+    pub tools_file: Option<PathBuf>,
+}
+
+impl Default for CommandsConfig {
+    fn default() -> Self {
+        CommandsConfig {
+            translate_language: "english".to_string(),
+            grammar_language: "Chinese".to_string(),
+            summary_max_tokens: 6000,
+            summary_proxy: None,
+            inline_debounce_ms: 800,
+            tools_file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads `path`, or the first of the default config files that exists, then applies
+    /// environment variable overrides. Without any config file, defaults are used.
+    pub fn load(path: Option<&Path>) -> Result<Config, anyhow::Error> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => DEFAULT_CONFIG_FILES
+                .iter()
+                .map(PathBuf::from)
+                .find(|path| path.exists()),
+        };
+        let mut config = match path {
+            Some(path) => Config::read(&path)?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Config, anyhow::Error> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read config file {path:?}"))?;
+        let config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(anyhow::Error::from),
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(anyhow::Error::from),
+            _ => anyhow::bail!("Config file {path:?} must be a .toml, .yaml or .yml file"),
+        };
+        config.with_context(|| format!("Invalid config file {path:?}"))
+    }
+
+    fn apply_env(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(token) = env("TELOXIDE_TOKEN") {
+            self.telegram.token = token;
+        }
+        if let Some(proxy) = env("TELOXIDE_PROXY") {
+            self.telegram.proxy = Some(proxy);
+        }
+        if let Some(id) = env_parse("ADMIN_CHAT_ID")? {
+            self.telegram.admin_chat_id = Some(id);
+        }
+        if let Some(url) = env_parse("WEBHOOK_URL")? {
+            self.webhook.url = Some(url);
+        }
+        if let Some(listen) = env_parse("WEBHOOK_LISTEN")? {
+            self.webhook.listen = listen;
+        }
+        if let Some(secret_token) = env("WEBHOOK_SECRET_TOKEN") {
+            self.webhook.secret_token = Some(secret_token);
+        }
+        if let Some(api_key) = env("OPEN_AI_API_KEY") {
+            self.openai.api_key = api_key;
+        }
+        if let Some(model) = env("OPEN_AI_MODEL") {
+            self.openai.model = model;
+        }
+        if let Some(url) = env_parse("OPEN_AI_URL")? {
+            self.openai.url = url;
+        }
+        if let Some(proxy) = env("OPEN_AI_PROXY") {
+            self.openai.proxy = Some(proxy);
+        }
+        if let Some(proxy) = env("SUMMARY_PROXY") {
+            self.commands.summary_proxy = Some(proxy);
+        }
+        if let Some(url) = env_parse("TRANSCRIPTION_URL")? {
+            self.transcription.url = url;
+        }
+        if let Some(api_key) = env("TRANSCRIPTION_API_KEY") {
+            self.transcription.api_key = Some(api_key);
+        }
+        if let Some(url) = env_parse("SPEECH_URL")? {
+            self.speech.url = url;
+        }
+        if let Some(api_key) = env("SPEECH_API_KEY") {
+            self.speech.api_key = Some(api_key);
+        }
+        if let Some(dir) = env("STORAGE_DIR") {
+            self.storage.dir = PathBuf::from(dir);
+        }
+        if let Some(backend) = env_parse("STORAGE_BACKEND")? {
+            self.storage.backend = backend;
+        }
+        if let Some(dialogues) = env("DIALOGUE_STORAGE") {
+            self.storage.dialogues = dialogues;
+        }
+        Ok(())
+    }
+
+    /// Checks everything needed to run the bot, reporting all problems at once.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let mut problems = vec![];
+        if self.telegram.token.trim().is_empty() {
+            problems.push("telegram.token is not set (or set TELOXIDE_TOKEN)".to_string());
+        }
+        for (name, proxy) in [
+            ("telegram.proxy", &self.telegram.proxy),
+            ("openai.proxy", &self.openai.proxy),
+            ("commands.summary_proxy", &self.commands.summary_proxy),
+        ] {
+            if let Some(proxy) = proxy {
+                if let Err(e) = reqwest::Proxy::all(proxy) {
+                    problems.push(format!("{name} '{proxy}' is not a valid proxy URL: {e}"));
+                }
+            }
+        }
+        if let Some(url) = &self.webhook.url {
+            if url.scheme() != "https" {
+                problems.push(format!("webhook.url must be an https URL, got {url}"));
+            }
+        }
+        if let Some(path) = &self.webhook.path {
+            if !path.starts_with('/') {
+                problems.push(format!("webhook.path must start with '/', got '{path}'"));
+            }
+        }
+        if let Some(secret_token) = &self.webhook.secret_token {
+            // Telegram's limits for the X-Telegram-Bot-Api-Secret-Token header.
+            let valid_chars = secret_token
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if !valid_chars || secret_token.is_empty() || secret_token.len() > 256 {
+                problems.push(
+                    "webhook.secret_token must be 1 to 256 characters of A-Z, a-z, 0-9, _ and -"
+                        .to_string(),
+                );
+            }
+        }
+        if self.openai.api_key.trim().is_empty() {
+            problems.push("openai.api_key is not set (or set OPEN_AI_API_KEY)".to_string());
+        }
+        if self.openai.model.trim().is_empty() {
+            problems.push("openai.model must not be empty".to_string());
+        }
+        let url = &self.openai.url;
+        if !matches!(url.scheme(), "http" | "https") || url.cannot_be_a_base() {
+            problems.push(format!("openai.url must be an http(s) base URL, got {url}"));
+        } else if url.query().is_some() || url.fragment().is_some() {
+            problems.push(format!(
+                "openai.url must not have a query or fragment, got {url}"
+            ));
+        }
+        if self.transcription.enabled && self.transcription.model.trim().is_empty() {
+            problems.push("transcription.model must not be empty".to_string());
+        }
+        if self.speech.model.trim().is_empty() {
+            problems.push("speech.model must not be empty".to_string());
+        }
+        if self.speech.voice.trim().is_empty() {
+            problems.push("speech.voice must not be empty".to_string());
+        }
+        if !(MIN_SPEECH_SPEED..=MAX_SPEECH_SPEED).contains(&self.speech.speed) {
+            problems.push(format!(
+                "speech.speed must be between {MIN_SPEECH_SPEED} and {MAX_SPEECH_SPEED}"
+            ));
+        }
+        if self.commands.summary_max_tokens == 0 {
+            problems.push("commands.summary_max_tokens must be greater than 0".to_string());
+        }
+        if self.documents.max_chars == 0 {
+            problems.push("documents.max_chars must be greater than 0".to_string());
+        }
+        if self.documents.chunk_chars == 0 {
+            problems.push("documents.chunk_chars must be greater than 0".to_string());
+        }
+        if self.documents.passages == 0 {
+            problems.push("documents.passages must be greater than 0".to_string());
+        }
+        if let Some(embeddings) = &self.documents.embeddings {
+            if embeddings.model.trim().is_empty() {
+                problems.push("documents.embeddings.model must not be empty".to_string());
+            }
+        }
+        let dialogues = self.storage.dialogues.as_str();
+        if !matches!(dialogues, "sqlite" | "memory")
+            && !dialogues.starts_with("redis://")
+            && !dialogues.starts_with("rediss://")
+        {
+            problems.push(format!(
+                "storage.dialogues is '{dialogues}', expected sqlite, memory or a redis:// URL"
+            ));
+        }
+        if self.limits.max_history_messages == Some(0) {
+            problems.push("limits.max_history_messages must be greater than 0".to_string());
+        }
+        if self.limits.max_message_length == Some(0) {
+            problems.push("limits.max_message_length must be greater than 0".to_string());
+        }
+        for (name, limit) in [
+            ("rate_limits.user", &self.rate_limits.user),
+            ("rate_limits.chat", &self.rate_limits.chat),
+            ("rate_limits.global", &self.rate_limits.global),
+        ] {
+            if let Some(limit) = limit {
+                if limit.burst == 0 || limit.per_minute <= 0.0 || !limit.per_minute.is_finite() {
+                    problems.push(format!(
+                        "{name} needs a burst and per_minute greater than 0"
+                    ));
+                }
+            }
+        }
+        if self.commands.translate_language.trim().is_empty() {
+            problems.push("commands.translate_language must not be empty".to_string());
+        }
+        if self.commands.grammar_language.trim().is_empty() {
+            problems.push("commands.grammar_language must not be empty".to_string());
+        }
+        if !problems.is_empty() {
+            anyhow::bail!("Invalid configuration:\n  - {}", problems.join("\n  - "));
+        }
+        Ok(())
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_parse<T>(name: &str) -> Result<Option<T>, anyhow::Error>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    env(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid {name} '{value}': {e}"))
+        })
+        .transpose()
+}
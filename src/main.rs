@@ -1,6 +1,15 @@
+use std::path::Path;
+
+use clap::Parser;
 use log::info;
 
+use crate::cli::{Cli, Command};
+use crate::config::Config;
+use crate::storages::Backend;
+
 mod chat_gpt;
+mod cli;
+mod config;
 mod storages;
 mod telegram;
 mod utils;
@@ -8,8 +17,49 @@ mod utils;
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     pretty_env_logger::init();
-    info!("Starting ChatGPT telegram bot...");
 
-    telegram::startup().await?;
+    let cli = Cli::parse();
+    let mut config = Config::load(cli.config.as_deref())?;
+    if let Some(dir) = cli.storage_dir {
+        config.storage.dir = dir;
+    }
+    if let Some(model) = cli.model {
+        config.openai.model = model;
+    }
+
+    let open_storage = || storages::open(config.storage.backend, &config.storage.dir);
+    match cli.command.unwrap_or(Command::Run { webhook: false }) {
+        Command::Run { webhook } => {
+            config.validate()?;
+            if webhook && config.webhook.url.is_none() {
+                anyhow::bail!("Webhook mode needs webhook.url in the config (or WEBHOOK_URL)");
+            }
+            info!("Starting ChatGPT telegram bot...");
+            let storage = open_storage()?;
+            telegram::startup(storage, config).await?;
+        }
+        Command::Roles(command) => cli::roles(open_storage()?, command)?,
+        Command::Sessions(command) => cli::sessions(open_storage()?, command)?,
+        Command::Usage(command) => cli::usage(open_storage()?, command)?,
+        Command::CheckConfig => cli::check_config(&config)?,
+        Command::Migrate { from, to } => migrate(&config.storage.dir, from, to)?,
+    }
+    Ok(())
+}
+
+fn migrate(storage_dir: &Path, from: Backend, to: Backend) -> Result<(), anyhow::Error> {
+    let migrated = storages::migrate(
+        storages::open(from, storage_dir)?.as_ref(),
+        storages::open(to, storage_dir)?.as_ref(),
+    )?;
+    println!(
+        "Migrated {} roles, {} sessions, {} user settings, {} documents and {} usage records from {from:?} to {to:?} in {}.",
+        migrated.roles,
+        migrated.sessions,
+        migrated.user_settings,
+        migrated.documents,
+        migrated.usage,
+        storage_dir.display()
+    );
     Ok(())
 }
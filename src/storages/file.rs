@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::storages::{
+    default_roles, deserialize, deserialize_roles, serialize, serialize_roles, ChatsDocuments,
+    Document, FileFormat, Roles, Session, Sessions, Storage, Usage, UserSettings, UsersSettings,
+    ROLES_SCHEMA_VERSION,
+};
+
+/// How many previous versions of each file are kept.
+const BACKUPS: usize = 3;
+
+/// Numbers temporary files, so that concurrent writes of a file don't share one.
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps every kind of data in its own YAML or JSON file, with one file per session and one file
+/// per chat's documents.
+pub struct FileStorage {
+    format: FileFormat,
+    roles_file: PathBuf,
+    user_settings_file: PathBuf,
+    usage_file: PathBuf,
+    sessions_dir: PathBuf,
+    documents_dir: PathBuf,
+    /// Held while the usage file is read and rewritten, as usage is recorded concurrently.
+    usage_lock: Mutex<()>,
+    /// Held while the user settings file is read and rewritten, as users save theirs
+    /// concurrently.
+    user_settings_lock: Mutex<()>,
+}
+
+impl FileStorage {
+    pub fn new(dir: &Path, format: FileFormat) -> FileStorage {
+        let file = |name: &str| dir.join(format!("{name}.{}", format.extension()));
+        FileStorage {
+            format,
+            roles_file: file("roles"),
+            user_settings_file: file("user_settings"),
+            usage_file: file("usage"),
+            sessions_dir: dir.join("sessions"),
+            documents_dir: dir.join("documents"),
+            usage_lock: Mutex::new(()),
+            user_settings_lock: Mutex::new(()),
+        }
+    }
+
+    fn session_file(&self, chat_id: i64) -> PathBuf {
+        self.sessions_dir
+            .join(format!("{chat_id}.{}", self.format.extension()))
+    }
+
+    fn documents_file(&self, chat_id: i64) -> PathBuf {
+        self.documents_dir
+            .join(format!("{chat_id}.{}", self.format.extension()))
+    }
+
+    /// Reads the files named after chat ids in `dir`.
+    fn read_chat_files<T: DeserializeOwned>(
+        &self,
+        dir: &Path,
+    ) -> Result<HashMap<i64, T>, anyhow::Error> {
+        let mut values = HashMap::new();
+        if !dir.exists() {
+            return Ok(values);
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(chat_id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            else {
+                continue;
+            };
+            if path.extension().and_then(|e| e.to_str()) == Some(self.format.extension()) {
+                values.insert(chat_id, self.read_or_recover(&path)?);
+            }
+        }
+        Ok(values)
+    }
+
+    fn read_with<T>(
+        &self,
+        path: &Path,
+        deserialize: impl Fn(&str) -> Result<T, anyhow::Error>,
+    ) -> Result<T, anyhow::Error> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Cannot read file {path:?}"))?;
+        deserialize(&contents).with_context(|| format!("Cannot deserialize file {path:?}"))
+    }
+
+    /// Like `read`, but falls back to the newest readable backup written by `write` when the file
+    /// is unusable.
+    fn read_or_recover<T: DeserializeOwned>(&self, path: &Path) -> Result<T, anyhow::Error> {
+        self.read_or_recover_with(path, |contents| deserialize(contents, self.format))
+    }
+
+    fn read_or_recover_with<T>(
+        &self,
+        path: &Path,
+        deserialize: impl Fn(&str) -> Result<T, anyhow::Error>,
+    ) -> Result<T, anyhow::Error> {
+        let e = match self.read_with(path, &deserialize) {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        for number in 1..=BACKUPS {
+            let backup = backup_path(path, number);
+            if !backup.exists() {
+                continue;
+            }
+            if let Ok(value) = self.read_with(&backup, &deserialize) {
+                warn!("Cannot read {path:?}, recovered from backup {backup:?}");
+                return Ok(value);
+            }
+        }
+        Err(e)
+    }
+
+    /// Like `read_or_recover`, but a missing file counts as empty.
+    fn read_or_default<T: DeserializeOwned + Default>(
+        &self,
+        path: &Path,
+    ) -> Result<T, anyhow::Error> {
+        if path.exists() || has_backup(path) {
+            self.read_or_recover(path)
+        } else {
+            Ok(T::default())
+        }
+    }
+
+    fn write<T: Serialize>(&self, path: &Path, value: &T) -> Result<(), anyhow::Error> {
+        let contents =
+            serialize(value, self.format).with_context(|| format!("Cannot serialize {path:?}"))?;
+        self.write_contents(path, &contents)
+    }
+
+    fn write_contents(&self, path: &Path, contents: &str) -> Result<(), anyhow::Error> {
+        write_atomically(path, contents.as_bytes())
+            .with_context(|| format!("Cannot write file {path:?}"))
+    }
+
+    /// Rewrites roles files of older schema versions, so they only need to be migrated once.
+    fn upgrade_roles(&self, (roles, version): (Roles, u32)) -> Result<Roles, anyhow::Error> {
+        if version < ROLES_SCHEMA_VERSION {
+            info!(
+                "Migrating {:?} from schema version {version} to {ROLES_SCHEMA_VERSION}",
+                self.roles_file
+            );
+            self.save_roles(&roles)?;
+        }
+        Ok(roles)
+    }
+}
+
+/// The `number`th newest backup of the file, from 1 to `BACKUPS`.
+fn backup_path(path: &Path, number: usize) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".bak.{number}"));
+    PathBuf::from(backup)
+}
+
+fn has_backup(path: &Path) -> bool {
+    (1..=BACKUPS).any(|number| backup_path(path, number).exists())
+}
+
+/// Replaces the file so that it's either fully written or left untouched, even on a crash or a
+/// full disk. The last `BACKUPS` versions are kept next to it as `.bak.1` (the newest) to
+/// `.bak.N`, and the oldest one is dropped on every write.
+fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(
+        ".{}.tmp",
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp_path = PathBuf::from(temp_path);
+
+    let result = (|| {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    if path.exists() {
+        for number in (1..BACKUPS).rev() {
+            let backup = backup_path(path, number);
+            if backup.exists() {
+                fs::rename(&backup, backup_path(path, number + 1))?;
+            }
+        }
+        fs::copy(path, backup_path(path, 1))?;
+    }
+    fs::rename(&temp_path, path)?;
+
+    // Make the rename itself durable. Directories can't be opened for syncing on every platform.
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+impl Storage for FileStorage {
+    fn roles_file(&self) -> Option<&Path> {
+        Some(&self.roles_file)
+    }
+
+    fn load_roles(&self) -> Result<Roles, anyhow::Error> {
+        if !self.roles_file.exists() && !has_backup(&self.roles_file) {
+            info!("No roles file found, creating {:?}", self.roles_file);
+            let roles = default_roles();
+            self.save_roles(&roles)?;
+            return Ok(roles);
+        }
+        let roles = self.read_or_recover_with(&self.roles_file, |contents| {
+            deserialize_roles(contents, self.format)
+        })?;
+        self.upgrade_roles(roles)
+    }
+
+    fn reload_roles(&self) -> Result<Roles, anyhow::Error> {
+        let roles = self.read_with(&self.roles_file, |contents| {
+            deserialize_roles(contents, self.format)
+        })?;
+        self.upgrade_roles(roles)
+    }
+
+    fn save_roles(&self, roles: &Roles) -> Result<(), anyhow::Error> {
+        let contents = serialize_roles(roles, self.format)
+            .with_context(|| format!("Cannot serialize {:?}", self.roles_file))?;
+        self.write_contents(&self.roles_file, &contents)
+    }
+
+    fn load_sessions(&self) -> Result<Sessions, anyhow::Error> {
+        self.read_chat_files(&self.sessions_dir)
+    }
+
+    fn save_session(&self, chat_id: i64, session: &Session) -> Result<(), anyhow::Error> {
+        fs::create_dir_all(&self.sessions_dir)
+            .with_context(|| format!("Cannot create directory {:?}", self.sessions_dir))?;
+        self.write(&self.session_file(chat_id), session)
+    }
+
+    fn load_user_settings(&self) -> Result<UsersSettings, anyhow::Error> {
+        self.read_or_default(&self.user_settings_file)
+    }
+
+    fn save_user_settings(
+        &self,
+        user_id: u64,
+        settings: &UserSettings,
+    ) -> Result<(), anyhow::Error> {
+        let _guard = self
+            .user_settings_lock
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut users_settings = self.load_user_settings()?;
+        users_settings.insert(user_id, settings.clone());
+        self.write(&self.user_settings_file, &users_settings)
+    }
+
+    fn load_documents(&self) -> Result<ChatsDocuments, anyhow::Error> {
+        self.read_chat_files(&self.documents_dir)
+    }
+
+    fn save_documents(&self, chat_id: i64, documents: &[Document]) -> Result<(), anyhow::Error> {
+        fs::create_dir_all(&self.documents_dir)
+            .with_context(|| format!("Cannot create directory {:?}", self.documents_dir))?;
+        self.write(&self.documents_file(chat_id), &documents)
+    }
+
+    fn load_usage(&self) -> Result<Vec<Usage>, anyhow::Error> {
+        self.read_or_default(&self.usage_file)
+    }
+
+    fn record_usage(&self, usage: &Usage) -> Result<(), anyhow::Error> {
+        let _guard = self.usage_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries = self.load_usage()?;
+        match entries.iter_mut().find(|entry| entry.is_same_entry(usage)) {
+            Some(entry) => entry.add(usage),
+            None => entries.push(usage.clone()),
+        }
+        self.write(&self.usage_file, &entries)
+    }
+
+    fn replace_usage(&self, usage: &[Usage]) -> Result<(), anyhow::Error> {
+        let _guard = self.usage_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.write(&self.usage_file, &usage)
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::chat_gpt::ChatMessage;
+use crate::utils::template;
+use crate::utils::template::Variables;
+
+pub use file::FileStorage;
+pub use sqlite::SqliteStorage;
+
+mod file;
+mod sqlite;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Role {
+    pub system: String,
+    /// Short summary shown in role lists instead of the full system prompt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Message sent by the bot right after switching to the role.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub greeting: Option<String>,
+    /// Few-shot user/assistant pairs injected after the system message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<Example>,
+    /// Emoji shown in front of the role name on keyboard buttons.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// Questions asked for `{{name}}` placeholders that the user's settings don't provide.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub variables: HashMap<String, String>,
+    /// Answers are also sent as voice messages when set, unless the chat turned them off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speech: Option<Speech>,
+}
+
+/// How a role's answers are read out. Unset values come from the speech config.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Speech {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Example {
+    pub user: String,
+    pub assistant: String,
+}
+
+impl Role {
+    pub fn new(system: String) -> Role {
+        Role {
+            system,
+            ..Default::default()
+        }
+    }
+
+    /// The messages a new session with this role starts with.
+    pub fn initial_conversation(&self, variables: &Variables) -> Vec<ChatMessage> {
+        let mut conversation = vec![ChatMessage::new_system(&template::render(
+            &self.system,
+            variables,
+        ))];
+        for example in &self.examples {
+            conversation.push(ChatMessage::new_user(&template::render(
+                &example.user,
+                variables,
+            )));
+            conversation.push(ChatMessage::new_assistant(&template::render(
+                &example.assistant,
+                variables,
+            )));
+        }
+        conversation
+    }
+
+    /// Template variables used anywhere in the role.
+    pub fn placeholders(&self) -> Vec<String> {
+        let mut names = template::placeholders(&self.system);
+        let greeting = self.greeting.iter().map(String::as_str);
+        let examples = self
+            .examples
+            .iter()
+            .flat_map(|e| [e.user.as_str(), e.assistant.as_str()]);
+        for text in greeting.chain(examples) {
+            for name in template::placeholders(text) {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    pub fn display_name(&self, name: &str) -> String {
+        match &self.icon {
+            Some(icon) => format!("{icon} {name}"),
+            None => name.to_string(),
+        }
+    }
+
+    /// The description, or the first line of the system prompt when there is none.
+    pub fn summary(&self) -> String {
+        const MAX_CHARS: usize = 60;
+
+        if let Some(description) = &self.description {
+            return description.clone();
+        }
+        let first_line = self.system.lines().next().unwrap_or_default();
+        if first_line.chars().count() > MAX_CHARS || self.system.lines().nth(1).is_some() {
+            let truncated: String = first_line.chars().take(MAX_CHARS).collect();
+            format!("{}…", truncated.trim_end())
+        } else {
+            first_line.to_string()
+        }
+    }
+}
+
+pub type Roles = HashMap<String, Role>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UserSettings {
+    /// Values for role template variables, such as `language` or `tone`.
+    #[serde(default)]
+    pub variables: Variables,
+}
+
+/// User settings keyed by Telegram user id.
+pub type UsersSettings = HashMap<u64, UserSettings>;
+
+/// A chat's conversation with the bot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub role: String,
+    /// Template variables the role's prompts were rendered with.
+    #[serde(default)]
+    pub variables: Variables,
+    #[serde(default)]
+    pub history: Vec<ChatMessage>,
+    /// Files uploaded without a question, attached to the next one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<String>,
+    /// Whether answers are sent as voice messages, set with /voice. Follows the role when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice: Option<bool>,
+    /// Changes whenever the conversation is restarted, so that answers still on their way for
+    /// the previous conversation are dropped. Not persisted.
+    #[serde(skip)]
+    pub generation: u64,
+}
+
+/// Sessions keyed by Telegram chat id.
+pub type Sessions = HashMap<i64, Session>;
+
+/// A file uploaded to a chat, split into passages that are looked up for questions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Document {
+    pub name: String,
+    /// Date the document was added, as YYYY-MM-DD.
+    pub added: String,
+    pub chunks: Vec<Chunk>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chunk {
+    pub text: String,
+    /// Embedding of the text, when embeddings are configured.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub embedding: Vec<f32>,
+}
+
+/// Documents keyed by Telegram chat id.
+pub type ChatsDocuments = HashMap<i64, Vec<Document>>;
+
+/// Completion usage of one user with one model on one day.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Usage {
+    pub date: String,
+    pub user_id: u64,
+    pub model: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    fn is_same_entry(&self, other: &Usage) -> bool {
+        self.date == other.date && self.user_id == other.user_id && self.model == other.model
+    }
+
+    pub fn add(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+pub trait Storage: Send + Sync {
+    /// The file roles are kept in, for backends where it can be edited by hand.
+    fn roles_file(&self) -> Option<&Path> {
+        None
+    }
+    fn load_roles(&self) -> Result<Roles, anyhow::Error>;
+    /// Like `load_roles`, but reports a broken roles file instead of recovering from a backup,
+    /// so that mistakes in hand edits are noticed.
+    fn reload_roles(&self) -> Result<Roles, anyhow::Error> {
+        self.load_roles()
+    }
+    fn save_roles(&self, roles: &Roles) -> Result<(), anyhow::Error>;
+    fn load_sessions(&self) -> Result<Sessions, anyhow::Error>;
+    fn save_session(&self, chat_id: i64, session: &Session) -> Result<(), anyhow::Error>;
+    fn load_user_settings(&self) -> Result<UsersSettings, anyhow::Error>;
+    fn save_user_settings(
+        &self,
+        user_id: u64,
+        settings: &UserSettings,
+    ) -> Result<(), anyhow::Error>;
+    fn load_documents(&self) -> Result<ChatsDocuments, anyhow::Error>;
+    fn save_documents(&self, chat_id: i64, documents: &[Document]) -> Result<(), anyhow::Error>;
+    fn load_usage(&self) -> Result<Vec<Usage>, anyhow::Error>;
+    /// Adds `usage` to the entry of the same date, user and model.
+    fn record_usage(&self, usage: &Usage) -> Result<(), anyhow::Error>;
+    /// Replaces all recorded usage with `usage`.
+    fn replace_usage(&self, usage: &[Usage]) -> Result<(), anyhow::Error>;
+}
+
+pub type StorageRef = Arc<dyn Storage>;
+
+pub const DEFAULT_STORAGE_DIR: &str = "storage";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Yaml,
+    Json,
+    Sqlite,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "yaml" => Ok(Backend::Yaml),
+            "json" => Ok(Backend::Json),
+            "sqlite" => Ok(Backend::Sqlite),
+            _ => anyhow::bail!("Unknown storage backend '{s}', expected yaml, json or sqlite"),
+        }
+    }
+}
+
+/// Opens the backend's storage in `dir`, creating the directory on first run.
+pub fn open(backend: Backend, dir: &Path) -> Result<StorageRef, anyhow::Error> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Cannot create storage directory {dir:?}"))?;
+    Ok(match backend {
+        Backend::Yaml => Arc::new(FileStorage::new(dir, FileFormat::Yaml)),
+        Backend::Json => Arc::new(FileStorage::new(dir, FileFormat::Json)),
+        Backend::Sqlite => Arc::new(SqliteStorage::open(&dir.join("bot.sqlite"))?),
+    })
+}
+
+/// Copies all data from one backend to another.
+/// How many of each kind of data `migrate` copied.
+pub struct Migrated {
+    pub roles: usize,
+    pub sessions: usize,
+    pub user_settings: usize,
+    pub documents: usize,
+    pub usage: usize,
+}
+
+pub fn migrate(from: &dyn Storage, to: &dyn Storage) -> Result<Migrated, anyhow::Error> {
+    let roles = from.load_roles()?;
+    to.save_roles(&roles)?;
+    let sessions = from.load_sessions()?;
+    for (chat_id, session) in &sessions {
+        to.save_session(*chat_id, session)?;
+    }
+    let user_settings = from.load_user_settings()?;
+    for (user_id, settings) in &user_settings {
+        to.save_user_settings(*user_id, settings)?;
+    }
+    let documents = from.load_documents()?;
+    for (chat_id, chat_documents) in &documents {
+        to.save_documents(*chat_id, chat_documents)?;
+    }
+    // Replaced rather than added to, so that migrating twice doesn't count usage twice.
+    let usage = from.load_usage()?;
+    to.replace_usage(&usage)?;
+    Ok(Migrated {
+        roles: roles.len(),
+        sessions: sessions.len(),
+        user_settings: user_settings.len(),
+        documents: documents.values().map(Vec::len).sum(),
+        usage: usage.len(),
+    })
+}
+
+/// Version of the roles file layout written by this build.
+///
+/// 1. A bare map of role names to roles.
+/// 2. The map moved under `roles`, next to a `version` field.
+pub const ROLES_SCHEMA_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct RolesDocument {
+    version: u32,
+    roles: Roles,
+}
+
+/// Reads the `version` of a roles document without caring about the rest of it.
+#[derive(Deserialize)]
+struct RolesDocumentVersion {
+    version: Option<u32>,
+}
+
+/// Parses a roles file of any known schema version, migrating it to the current one.
+///
+/// Also returns the version the file was written with.
+pub fn deserialize_roles(
+    contents: &str,
+    format: FileFormat,
+) -> Result<(Roles, u32), anyhow::Error> {
+    let version = deserialize::<RolesDocumentVersion>(contents, format)
+        .ok()
+        .and_then(|document| document.version)
+        .unwrap_or(1);
+    match version {
+        1 => Ok((deserialize(contents, format)?, version)),
+        ROLES_SCHEMA_VERSION => Ok((deserialize::<RolesDocument>(contents, format)?.roles, version)),
+        _ => anyhow::bail!(
+            "Roles schema version {version} is not supported, the latest known version is {ROLES_SCHEMA_VERSION}"
+        ),
+    }
+}
+
+pub fn serialize_roles(roles: &Roles, format: FileFormat) -> Result<String, anyhow::Error> {
+    serialize(
+        &RolesDocument {
+            version: ROLES_SCHEMA_VERSION,
+            roles: roles.clone(),
+        },
+        format,
+    )
+}
+
+/// The roles a new installation starts with.
+pub fn default_roles() -> Roles {
+    deserialize_roles(include_str!("default_roles.yaml"), FileFormat::Yaml)
+        .expect("Built-in default roles must be valid")
+        .0
+}
+
+/// Longest role name that still fits into Telegram's 64 bytes of callback data.
+const MAX_ROLE_NAME_BYTES: usize = 48;
+
+/// Speeds OpenAI's speech endpoint accepts.
+pub const MIN_SPEECH_SPEED: f64 = 0.25;
+pub const MAX_SPEECH_SPEED: f64 = 4.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    Yaml,
+    Json,
+}
+
+impl FileFormat {
+    pub fn from_file_name(file_name: &str) -> FileFormat {
+        if file_name.to_lowercase().ends_with(".json") {
+            FileFormat::Json
+        } else {
+            FileFormat::Yaml
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            FileFormat::Yaml => "yaml",
+            FileFormat::Json => "json",
+        }
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(FileFormat::Yaml),
+            "json" => Ok(FileFormat::Json),
+            _ => anyhow::bail!("Unknown format '{s}', expected yaml or json"),
+        }
+    }
+}
+
+pub fn serialize<T: Serialize>(value: &T, format: FileFormat) -> Result<String, anyhow::Error> {
+    match format {
+        FileFormat::Yaml => serde_yaml::to_string(value).context("Cannot serialize to YAML"),
+        FileFormat::Json => serde_json::to_string_pretty(value).context("Cannot serialize to JSON"),
+    }
+}
+
+pub fn deserialize<T: DeserializeOwned>(
+    contents: &str,
+    format: FileFormat,
+) -> Result<T, anyhow::Error> {
+    match format {
+        FileFormat::Yaml => serde_yaml::from_str(contents).context("Invalid YAML"),
+        FileFormat::Json => serde_json::from_str(contents).context("Invalid JSON"),
+    }
+}
+
+/// Checks that a role name can be shown in role keyboards.
+pub fn validate_role_name(name: &str) -> Result<(), anyhow::Error> {
+    if name.trim().is_empty() {
+        anyhow::bail!("Role names must not be empty");
+    }
+    if name.len() > MAX_ROLE_NAME_BYTES {
+        anyhow::bail!("Role name '{name}' is longer than {MAX_ROLE_NAME_BYTES} bytes");
+    }
+    Ok(())
+}
+
+/// Checks the constraints the bot relies on but the file format can't express.
+pub fn validate_roles(roles: &Roles) -> Result<(), anyhow::Error> {
+    for (name, role) in roles {
+        validate_role_name(name)?;
+        if role.system.trim().is_empty() {
+            anyhow::bail!("Role '{name}' has an empty system prompt");
+        }
+        if let Some(speed) = role.speech.as_ref().and_then(|speech| speech.speed) {
+            if !(MIN_SPEECH_SPEED..=MAX_SPEECH_SPEED).contains(&speed) {
+                anyhow::bail!(
+                    "Role '{name}' has a speech speed of {speed}, expected {MIN_SPEECH_SPEED} to {MAX_SPEECH_SPEED}"
+                );
+            }
+        }
+    }
+    Ok(())
+}
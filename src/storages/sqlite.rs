@@ -0,0 +1,235 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use rusqlite::{params, Connection};
+
+use crate::storages::{
+    default_roles, ChatsDocuments, Document, Roles, Session, Sessions, Storage, Usage,
+    UserSettings, UsersSettings,
+};
+
+/// Version of the database schema, kept in SQLite's `user_version`.
+const SCHEMA_VERSION: u32 = 2;
+
+/// Keeps all data in one SQLite database, with structured values stored as JSON.
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage, anyhow::Error> {
+        let connection =
+            Connection::open(path).with_context(|| format!("Cannot open database {path:?}"))?;
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            anyhow::bail!(
+                "Database {path:?} has schema version {version}, the latest known version is {SCHEMA_VERSION}"
+            );
+        }
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS roles (
+                name TEXT PRIMARY KEY,
+                role TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS sessions (
+                chat_id INTEGER PRIMARY KEY,
+                session TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS user_settings (
+                user_id INTEGER PRIMARY KEY,
+                settings TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS documents (
+                chat_id INTEGER PRIMARY KEY,
+                documents TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS usage (
+                date TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                model TEXT NOT NULL,
+                requests INTEGER NOT NULL,
+                prompt_tokens INTEGER NOT NULL,
+                completion_tokens INTEGER NOT NULL,
+                PRIMARY KEY (date, user_id, model)
+            );",
+        )?;
+        let storage = SqliteStorage {
+            connection: Mutex::new(connection),
+        };
+        if version == 0 {
+            storage.save_roles(&default_roles())?;
+        }
+        // Version 2 only added the documents table, created above.
+        if version < SCHEMA_VERSION {
+            storage
+                .connection()
+                .pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        Ok(storage)
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_roles(&self) -> Result<Roles, anyhow::Error> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT name, role FROM roles")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?;
+        let mut roles = Roles::new();
+        for row in rows {
+            let (name, role): (String, String) = row?;
+            let role = serde_json::from_str(&role)
+                .with_context(|| format!("Cannot deserialize role '{name}'"))?;
+            roles.insert(name, role);
+        }
+        Ok(roles)
+    }
+
+    fn save_roles(&self, roles: &Roles) -> Result<(), anyhow::Error> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM roles", [])?;
+        for (name, role) in roles {
+            transaction.execute(
+                "INSERT INTO roles (name, role) VALUES (?1, ?2)",
+                params![name, serde_json::to_string(role)?],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn load_sessions(&self) -> Result<Sessions, anyhow::Error> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT chat_id, session FROM sessions")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?;
+        let mut sessions = Sessions::new();
+        for row in rows {
+            let (chat_id, session): (i64, String) = row?;
+            let session = serde_json::from_str(&session)
+                .with_context(|| format!("Cannot deserialize session of chat {chat_id}"))?;
+            sessions.insert(chat_id, session);
+        }
+        Ok(sessions)
+    }
+
+    fn save_session(&self, chat_id: i64, session: &Session) -> Result<(), anyhow::Error> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO sessions (chat_id, session) VALUES (?1, ?2)",
+            params![chat_id, serde_json::to_string(session)?],
+        )?;
+        Ok(())
+    }
+
+    fn load_user_settings(&self) -> Result<UsersSettings, anyhow::Error> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT user_id, settings FROM user_settings")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?;
+        let mut users_settings = UsersSettings::new();
+        for row in rows {
+            let (user_id, settings): (i64, String) = row?;
+            let settings = serde_json::from_str(&settings)
+                .with_context(|| format!("Cannot deserialize settings of user {user_id}"))?;
+            users_settings.insert(user_id as u64, settings);
+        }
+        Ok(users_settings)
+    }
+
+    fn save_user_settings(
+        &self,
+        user_id: u64,
+        settings: &UserSettings,
+    ) -> Result<(), anyhow::Error> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO user_settings (user_id, settings) VALUES (?1, ?2)",
+            params![user_id as i64, serde_json::to_string(settings)?],
+        )?;
+        Ok(())
+    }
+
+    fn load_documents(&self) -> Result<ChatsDocuments, anyhow::Error> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT chat_id, documents FROM documents")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?;
+        let mut chats_documents = ChatsDocuments::new();
+        for row in rows {
+            let (chat_id, documents): (i64, String) = row?;
+            let documents = serde_json::from_str(&documents)
+                .with_context(|| format!("Cannot deserialize documents of chat {chat_id}"))?;
+            chats_documents.insert(chat_id, documents);
+        }
+        Ok(chats_documents)
+    }
+
+    fn save_documents(&self, chat_id: i64, documents: &[Document]) -> Result<(), anyhow::Error> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO documents (chat_id, documents) VALUES (?1, ?2)",
+            params![chat_id, serde_json::to_string(documents)?],
+        )?;
+        Ok(())
+    }
+
+    fn load_usage(&self) -> Result<Vec<Usage>, anyhow::Error> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT date, user_id, model, requests, prompt_tokens, completion_tokens FROM usage",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok(Usage {
+                date: row.get(0)?,
+                user_id: row.get::<_, i64>(1)? as u64,
+                model: row.get(2)?,
+                requests: row.get::<_, i64>(3)? as u64,
+                prompt_tokens: row.get::<_, i64>(4)? as u64,
+                completion_tokens: row.get::<_, i64>(5)? as u64,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn record_usage(&self, usage: &Usage) -> Result<(), anyhow::Error> {
+        self.connection().execute(
+            "INSERT INTO usage (date, user_id, model, requests, prompt_tokens, completion_tokens)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (date, user_id, model) DO UPDATE SET
+                requests = requests + excluded.requests,
+                prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                completion_tokens = completion_tokens + excluded.completion_tokens",
+            params![
+                usage.date,
+                usage.user_id as i64,
+                usage.model,
+                usage.requests as i64,
+                usage.prompt_tokens as i64,
+                usage.completion_tokens as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn replace_usage(&self, usage: &[Usage]) -> Result<(), anyhow::Error> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM usage", [])?;
+        for usage in usage {
+            transaction.execute(
+                "INSERT INTO usage (date, user_id, model, requests, prompt_tokens, completion_tokens)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    usage.date,
+                    usage.user_id as i64,
+                    usage.model,
+                    usage.requests as i64,
+                    usage.prompt_tokens as i64,
+                    usage.completion_tokens as i64
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::info;
+use teloxide::prelude::*;
+use teloxide::types::MessageId;
+use tokio::task::JoinHandle;
+
+use crate::telegram::startup::ThrottledBot;
+
+pub type ChatQueueRef = Arc<ChatQueue>;
+
+/// Answers each chat's messages one turn at a time, while different chats are answered in
+/// parallel.
+///
+/// Messages that arrive within the debounce window of each other, or while the chat's previous
+/// turn is still being answered, are answered together in a single turn.
+pub struct ChatQueue {
+    debounce: Duration,
+    chats: Mutex<HashMap<ChatId, ChatWork>>,
+}
+
+/// Messages waiting for a chat's worker, which runs as long as there is something to answer.
+struct ChatWork {
+    pending: Vec<Message>,
+    worker: JoinHandle<()>,
+    /// Message showing that an answer is being written, replaced when the turn is cancelled.
+    status_message: Option<MessageId>,
+}
+
+impl ChatQueue {
+    pub fn new(debounce: Duration) -> ChatQueue {
+        ChatQueue {
+            debounce,
+            chats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Queues `msg`, starting a worker that calls `answer` with each turn's messages if the
+    /// chat doesn't have one yet.
+    pub fn push<F, Fut>(self: &Arc<Self>, msg: Message, answer: F)
+    where
+        F: Fn(Vec<Message>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let chat_id = msg.chat.id;
+        let mut chats = self.chats.lock().unwrap();
+        match chats.get_mut(&chat_id) {
+            Some(work) => work.pending.push(msg),
+            None => {
+                let worker = tokio::spawn(self.clone().work(chat_id, answer));
+                chats.insert(
+                    chat_id,
+                    ChatWork {
+                        pending: vec![msg],
+                        worker,
+                        status_message: None,
+                    },
+                );
+            }
+        }
+    }
+
+    async fn work<F, Fut>(self: Arc<Self>, chat_id: ChatId, answer: F)
+    where
+        F: Fn(Vec<Message>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        loop {
+            self.debounce(chat_id).await;
+            let Some(messages) = self.take_pending(chat_id) else {
+                return;
+            };
+            answer(messages).await;
+        }
+    }
+
+    /// Waits until no new message arrived for a whole debounce window.
+    async fn debounce(&self, chat_id: ChatId) {
+        if self.debounce.is_zero() {
+            return;
+        }
+        let mut pending = self.pending_count(chat_id);
+        while pending > 0 {
+            tokio::time::sleep(self.debounce).await;
+            let now = self.pending_count(chat_id);
+            if now == pending {
+                break;
+            }
+            pending = now;
+        }
+    }
+
+    fn pending_count(&self, chat_id: ChatId) -> usize {
+        let chats = self.chats.lock().unwrap();
+        chats.get(&chat_id).map_or(0, |work| work.pending.len())
+    }
+
+    /// The messages of the chat's next turn. When there are none the worker is done, and is
+    /// removed so that the next message starts a new one.
+    fn take_pending(&self, chat_id: ChatId) -> Option<Vec<Message>> {
+        let mut chats = self.chats.lock().unwrap();
+        let work = chats.get_mut(&chat_id)?;
+        work.status_message = None;
+        if work.pending.is_empty() {
+            chats.remove(&chat_id);
+            return None;
+        }
+        Some(std::mem::take(&mut work.pending))
+    }
+
+    /// Remembers the message showing the current turn is being answered.
+    pub fn set_status_message(&self, chat_id: ChatId, message_id: MessageId) {
+        if let Some(work) = self.chats.lock().unwrap().get_mut(&chat_id) {
+            work.status_message = Some(message_id);
+        }
+    }
+
+    /// Stops answering the chat and drops its queued messages. Returns whether there was
+    /// anything to cancel.
+    pub async fn cancel(&self, bot: &ThrottledBot, chat_id: ChatId) -> bool {
+        let Some(work) = self.chats.lock().unwrap().remove(&chat_id) else {
+            return false;
+        };
+        work.worker.abort();
+        info!("Cancelled answering chat {chat_id}");
+        if let Some(message_id) = work.status_message {
+            // The status message may already be gone, that's fine.
+            let _ = bot.edit_message_text(chat_id, message_id, "Stopped.").await;
+        }
+        true
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use teloxide::dispatching::dialogue::serializer::Json;
+use teloxide::dispatching::dialogue::{
+    ErasedStorage, InMemStorage, RedisStorage, SqliteStorage, Storage,
+};
+
+use crate::config::StorageConfig;
+use crate::telegram::startup::State;
+
+pub type DialogueStorage = ErasedStorage<State>;
+
+/// Opens the storage selected by `storage.dialogues`: `sqlite` (kept in the storage directory),
+/// `memory`, or a `redis://` URL.
+pub async fn open_dialogue_storage(
+    config: &StorageConfig,
+) -> Result<Arc<DialogueStorage>, anyhow::Error> {
+    Ok(match config.dialogues.as_str() {
+        "memory" => InMemStorage::<State>::new().erase(),
+        "sqlite" => {
+            let path = config.dir.join("dialogues.sqlite");
+            let path = path
+                .to_str()
+                .with_context(|| format!("Invalid dialogue storage path {path:?}"))?;
+            SqliteStorage::open(path, Json)
+                .await
+                .with_context(|| format!("Cannot open dialogue storage {path}"))?
+                .erase()
+        }
+        url => RedisStorage::open(url, Json)
+            .await
+            .context("Cannot connect to the Redis dialogue storage")?
+            .erase(),
+    })
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use teloxide::types::ChatId;
+
+use crate::storages::{ChatsDocuments, Chunk, Document, StorageRef};
+use crate::utils::bm25::Bm25Index;
+
+/// Passages taken from each ranking before the rankings are combined.
+const CANDIDATES: usize = 20;
+/// Reciprocal rank fusion constant, keeping the first few ranks from dominating.
+const RRF_K: f64 = 60.0;
+
+pub type DocumentStoreRef = Arc<DocumentStore>;
+
+/// The documents uploaded to each chat, indexed for finding passages relevant to a question.
+pub struct DocumentStore {
+    storage: StorageRef,
+    chats: Mutex<HashMap<ChatId, ChatDocuments>>,
+}
+
+struct ChatDocuments {
+    documents: Vec<Document>,
+    /// Indexes the chunks of all documents, in order.
+    index: Bm25Index,
+}
+
+impl ChatDocuments {
+    fn new(documents: Vec<Document>) -> ChatDocuments {
+        let index = Bm25Index::new(
+            documents
+                .iter()
+                .flat_map(|document| document.chunks.iter().map(|chunk| chunk.text.as_str())),
+        );
+        ChatDocuments { documents, index }
+    }
+
+    fn chunks(&self) -> impl Iterator<Item = (&Document, usize, &Chunk)> {
+        self.documents.iter().flat_map(|document| {
+            document
+                .chunks
+                .iter()
+                .enumerate()
+                .map(move |(i, chunk)| (document, i, chunk))
+        })
+    }
+}
+
+pub struct DocumentSummary {
+    pub name: String,
+    pub added: String,
+    pub passages: usize,
+}
+
+/// A passage of a document found for a question.
+pub struct Passage {
+    pub document: String,
+    /// Position of the passage in its document, from 1.
+    pub part: usize,
+    pub text: String,
+}
+
+impl DocumentStore {
+    pub fn new(storage: StorageRef, documents: ChatsDocuments) -> DocumentStore {
+        let chats = documents
+            .into_iter()
+            .filter(|(_, documents)| !documents.is_empty())
+            .map(|(chat_id, documents)| (ChatId(chat_id), ChatDocuments::new(documents)))
+            .collect();
+        DocumentStore {
+            storage,
+            chats: Mutex::new(chats),
+        }
+    }
+
+    fn chats(&self) -> MutexGuard<'_, HashMap<ChatId, ChatDocuments>> {
+        self.chats.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Adds a document to the chat, replacing any document of the same name.
+    pub fn add(&self, chat_id: ChatId, document: Document) -> Result<(), anyhow::Error> {
+        let mut chats = self.chats();
+        let mut documents = chats
+            .get(&chat_id)
+            .map(|chat| chat.documents.clone())
+            .unwrap_or_default();
+        documents.retain(|existing| existing.name != document.name);
+        documents.push(document);
+        self.storage.save_documents(chat_id.0, &documents)?;
+        chats.insert(chat_id, ChatDocuments::new(documents));
+        Ok(())
+    }
+
+    /// Removes the chat's document called `name`, returning whether there was one.
+    pub fn remove(&self, chat_id: ChatId, name: &str) -> Result<bool, anyhow::Error> {
+        let mut chats = self.chats();
+        let Some(chat) = chats.get(&chat_id) else {
+            return Ok(false);
+        };
+        if !chat.documents.iter().any(|document| document.name == name) {
+            return Ok(false);
+        }
+        let mut documents = chat.documents.clone();
+        documents.retain(|document| document.name != name);
+        self.storage.save_documents(chat_id.0, &documents)?;
+        if documents.is_empty() {
+            chats.remove(&chat_id);
+        } else {
+            chats.insert(chat_id, ChatDocuments::new(documents));
+        }
+        Ok(true)
+    }
+
+    pub fn list(&self, chat_id: ChatId) -> Vec<DocumentSummary> {
+        let chats = self.chats();
+        let Some(chat) = chats.get(&chat_id) else {
+            return vec![];
+        };
+        chat.documents
+            .iter()
+            .map(|document| DocumentSummary {
+                name: document.name.clone(),
+                added: document.added.clone(),
+                passages: document.chunks.len(),
+            })
+            .collect()
+    }
+
+    pub fn has_documents(&self, chat_id: ChatId) -> bool {
+        self.chats().contains_key(&chat_id)
+    }
+
+    /// The chat's passages most relevant to `query`, best first. Passages are ranked by the words
+    /// they share with the query, and by meaning as well when the query's embedding is given.
+    pub fn search(
+        &self,
+        chat_id: ChatId,
+        query: &str,
+        query_embedding: Option<&[f32]>,
+        limit: usize,
+    ) -> Vec<Passage> {
+        let chats = self.chats();
+        let Some(chat) = chats.get(&chat_id) else {
+            return vec![];
+        };
+
+        let mut rankings = vec![chat.index.search(query, CANDIDATES)];
+        if let Some(query_embedding) = query_embedding {
+            let mut similarities = chat
+                .chunks()
+                .enumerate()
+                .filter(|(_, (_, _, chunk))| !chunk.embedding.is_empty())
+                .map(|(i, (_, _, chunk))| (i, cosine_similarity(query_embedding, &chunk.embedding)))
+                .collect::<Vec<_>>();
+            similarities.sort_by(|a, b| b.1.total_cmp(&a.1));
+            similarities.truncate(CANDIDATES);
+            rankings.push(similarities);
+        }
+
+        let mut scores: HashMap<usize, f64> = HashMap::new();
+        for ranking in rankings {
+            for (rank, (i, _)) in ranking.into_iter().enumerate() {
+                *scores.entry(i).or_default() += 1.0 / (RRF_K + rank as f64 + 1.0);
+            }
+        }
+        let mut best = scores.into_iter().collect::<Vec<_>>();
+        best.sort_by(|a, b| b.1.total_cmp(&a.1));
+        best.truncate(limit);
+
+        let chunks = chat.chunks().collect::<Vec<_>>();
+        best.into_iter()
+            .map(|(i, _)| {
+                let (document, part, chunk) = chunks[i];
+                Passage {
+                    document: document.name.clone(),
+                    part: part + 1,
+                    text: chunk.text.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    let dot = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| *a as f64 * *b as f64)
+        .sum::<f64>();
+    let norm = |v: &[f32]| v.iter().map(|x| *x as f64 * *x as f64).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
@@ -0,0 +1,216 @@
+use anyhow::Context;
+use log::error;
+use teloxide::prelude::*;
+
+use crate::chat_gpt::{embed, ChatGptClient};
+use crate::config::DocumentsConfig;
+use crate::storages::{Chunk, Document};
+use crate::telegram::document_store::{DocumentStoreRef, Passage};
+use crate::telegram::message_helper::{download_file, MAX_DOWNLOAD_SIZE};
+use crate::telegram::startup::{HandlerResult, ThrottledBot};
+use crate::utils::documents::{chunk_text, extract_text};
+
+/// Downloads a document and extracts its text, returning the file name and the text. Returns
+/// `None`, after telling the user why, when the file can't be read.
+pub async fn read_document(
+    bot: &ThrottledBot,
+    msg: &Message,
+) -> Result<Option<(String, String)>, anyhow::Error> {
+    let Some(document) = msg.document() else {
+        return Ok(None);
+    };
+    let file_name = document
+        .file_name
+        .clone()
+        .unwrap_or_else(|| "file".to_string());
+    let reply = |text: String| {
+        bot.send_message(msg.chat.id, text)
+            .reply_to_message_id(msg.id)
+    };
+    if document.file.size > MAX_DOWNLOAD_SIZE {
+        reply(format!("{file_name} is too large to read.")).await?;
+        return Ok(None);
+    }
+
+    let mime_type = document
+        .mime_type
+        .as_ref()
+        .map(|mime| mime.essence_str().to_string());
+    let text = async {
+        let contents = download_file(bot, &document.file.id).await?;
+        let file_name = file_name.clone();
+        // Parsing PDFs is CPU bound, and may panic on malformed files.
+        tokio::task::spawn_blocking(move || {
+            extract_text(&file_name, mime_type.as_deref(), &contents)
+        })
+        .await
+        .context("Cannot read the file")?
+    }
+    .await;
+    match text {
+        Ok(text) if text.trim().is_empty() => {
+            reply(format!("{file_name} has no text to read.")).await?;
+            Ok(None)
+        }
+        Ok(text) => Ok(Some((file_name, text.trim().to_string()))),
+        Err(e) => {
+            reply(format!("Cannot read {file_name}: {e:#}")).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// A file's text with a header naming the file, to be attached to a question.
+pub fn attachment(file_name: &str, text: &str) -> String {
+    format!("File {file_name}:\n```\n{text}\n```")
+}
+
+/// Splits a file that doesn't fit in the question's attachments into passages and adds it to the
+/// chat's documents.
+pub async fn store_document(
+    bot: &ThrottledBot,
+    msg: &Message,
+    chat_gpt: &ChatGptClient,
+    document_store: &DocumentStoreRef,
+    config: &DocumentsConfig,
+    file_name: String,
+    text: &str,
+) -> Result<(), anyhow::Error> {
+    let texts = chunk_text(text, config.chunk_chars);
+    // Passages can still be found by their words without embeddings.
+    let (embeddings, embedding_failed) = match embed(chat_gpt, &texts).await {
+        Ok(embeddings) => (embeddings, false),
+        Err(e) => {
+            error!("Cannot embed {file_name}: {e:#}");
+            (None, true)
+        }
+    };
+    let mut embeddings = embeddings.unwrap_or_default().into_iter();
+    let chunks = texts
+        .into_iter()
+        .map(|text| Chunk {
+            text,
+            embedding: embeddings.next().unwrap_or_default(),
+        })
+        .collect::<Vec<_>>();
+    let passages = chunks.len();
+    document_store.add(
+        msg.chat.id,
+        Document {
+            name: file_name.clone(),
+            added: chrono::Local::now().format("%Y-%m-%d").to_string(),
+            chunks,
+        },
+    )?;
+    let mut reply = format!(
+        "{file_name} doesn't fit in the {} characters files attached to a question can take up \
+        together, so it was added to this chat's documents as {passages} passages. The passages \
+        relevant to your questions are sent with them. See /docs to manage documents.",
+        config.max_chars
+    );
+    if embedding_failed {
+        reply.push_str(
+            "\n\nIts passages couldn't be embedded, so they are only found by the words they \
+            share with your questions, not by meaning.",
+        );
+    }
+    bot.send_message(msg.chat.id, reply)
+        .reply_to_message_id(msg.id)
+        .await?;
+    Ok(())
+}
+
+/// The chat's passages most relevant to `query`, if it has any documents.
+pub async fn find_passages(
+    chat_gpt: &ChatGptClient,
+    document_store: &DocumentStoreRef,
+    config: &DocumentsConfig,
+    chat_id: ChatId,
+    query: &str,
+) -> Vec<Passage> {
+    if !document_store.has_documents(chat_id) {
+        return vec![];
+    }
+    let query_embedding = match embed(chat_gpt, &[query.to_string()]).await {
+        Ok(embeddings) => embeddings.and_then(|embeddings| embeddings.into_iter().next()),
+        Err(e) => {
+            error!("Cannot embed question in chat {chat_id}: {e:#}");
+            None
+        }
+    };
+    document_store.search(chat_id, query, query_embedding.as_deref(), config.passages)
+}
+
+/// A system message giving ChatGPT the passages, numbered for citing.
+pub fn passages_prompt(passages: &[Passage]) -> String {
+    let mut prompt = "Passages from documents uploaded to this chat follow. Use them if they are \
+        relevant to the question, and cite the passages you use by their number, like [1]."
+        .to_string();
+    for (i, passage) in passages.iter().enumerate() {
+        prompt.push_str(&format!(
+            "\n\n[{}] {}, part {}:\n{}",
+            i + 1,
+            passage.document,
+            passage.part,
+            passage.text
+        ));
+    }
+    prompt
+}
+
+/// The sources of the passages an answer cites, to show under it.
+pub fn cited_sources(answer: &str, passages: &[Passage]) -> Option<String> {
+    let sources = passages
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| answer.contains(&format!("[{}]", i + 1)))
+        .map(|(i, passage)| format!("[{}] {}, part {}", i + 1, passage.document, passage.part))
+        .collect::<Vec<_>>();
+    (!sources.is_empty()).then(|| format!("Sources:\n{}", sources.join("\n")))
+}
+
+/// Lists the chat's documents, or removes one by name or by its number in the list.
+pub async fn manage_documents(
+    bot: ThrottledBot,
+    msg: Message,
+    document_store: DocumentStoreRef,
+    input: String,
+) -> HandlerResult {
+    let documents = document_store.list(msg.chat.id);
+    let input = input.trim();
+    let text = match input.split_once(' ').unwrap_or((input, "")) {
+        ("" | "list", _) if documents.is_empty() => "No documents in this chat.".to_string(),
+        ("" | "list", _) => {
+            let lines = documents
+                .iter()
+                .enumerate()
+                .map(|(i, document)| {
+                    format!(
+                        "{}. {} ({} passages, added {})",
+                        i + 1,
+                        document.name,
+                        document.passages,
+                        document.added
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!("Documents:\n{}", lines.join("\n"))
+        }
+        ("remove", name) if !name.trim().is_empty() => {
+            let name = name.trim();
+            let name = match name.parse::<usize>() {
+                Ok(number) if (1..=documents.len()).contains(&number) => {
+                    documents[number - 1].name.as_str()
+                }
+                _ => name,
+            };
+            match document_store.remove(msg.chat.id, name)? {
+                true => format!("Document {name} removed."),
+                false => format!("No document named {name}."),
+            }
+        }
+        _ => "Usage: /docs list, or /docs remove <name or number>".to_string(),
+    };
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::error;
+use teloxide::prelude::*;
+use teloxide::types::{
+    InlineQueryResult, InlineQueryResultArticle, InputMessageContent, InputMessageContentText,
+};
+
+use crate::chat_gpt;
+use crate::chat_gpt::ChatGptRef;
+use crate::config::ConfigRef;
+use crate::storages::StorageRef;
+use crate::telegram::rate_limiter::RateLimiterRef;
+use crate::telegram::startup::{record_user_usage, HandlerResult, ThrottledBot};
+
+/// How much of an answer is shown under its title in the results.
+const DESCRIPTION_CHARS: usize = 100;
+/// Telegram's limit on the text of a message, which a chosen result is sent as, in UTF-16 code
+/// units.
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+pub type InlineQueriesRef = Arc<InlineQueries>;
+
+/// The latest inline query of each user. Telegram sends a query for every key typed, and only
+/// the one the user stopped typing at is answered.
+#[derive(Default)]
+pub struct InlineQueries {
+    latest: Mutex<HashMap<UserId, String>>,
+}
+
+impl InlineQueries {
+    /// Records `query_id` as the user's latest query, waits for `delay` and tells whether no
+    /// other query came from the user in the meantime.
+    async fn settle(&self, user_id: UserId, query_id: &str, delay: Duration) -> bool {
+        self.latest
+            .lock()
+            .unwrap()
+            .insert(user_id, query_id.to_string());
+        tokio::time::sleep(delay).await;
+        let mut latest = self.latest.lock().unwrap();
+        if latest.get(&user_id).map(String::as_str) != Some(query_id) {
+            return false;
+        }
+        latest.remove(&user_id);
+        true
+    }
+}
+
+/// The helper commands that can be used inline, as `@bot trans -lja hello`.
+#[derive(Clone, Copy)]
+enum InlineCommand {
+    Translate,
+    VariableNamer,
+    CheckGrammar,
+}
+
+impl InlineCommand {
+    fn title(self) -> &'static str {
+        match self {
+            InlineCommand::Translate => "Translation",
+            InlineCommand::VariableNamer => "Variable names",
+            InlineCommand::CheckGrammar => "Grammar check",
+        }
+    }
+}
+
+/// The command and its input, which take the same options as the bot commands.
+fn parse_query(query: &str) -> Option<(InlineCommand, String)> {
+    let query = query.trim();
+    let (name, input) = query.split_once(char::is_whitespace).unwrap_or((query, ""));
+    let command = match name.trim_start_matches('/') {
+        "trans" => InlineCommand::Translate,
+        "naming" => InlineCommand::VariableNamer,
+        "gramcheck" => InlineCommand::CheckGrammar,
+        _ => return None,
+    };
+    let input = input.trim();
+    (!input.is_empty()).then(|| (command, input.to_string()))
+}
+
+pub async fn inline_query_handler(
+    bot: ThrottledBot,
+    q: InlineQuery,
+    config: ConfigRef,
+    chat_gpt: ChatGptRef,
+    storage: StorageRef,
+    rate_limiter: RateLimiterRef,
+    inline_queries: InlineQueriesRef,
+) -> HandlerResult {
+    let Some((command, input)) = parse_query(&q.query) else {
+        bot.answer_inline_query(q.id, []).await?;
+        return Ok(());
+    };
+    let delay = Duration::from_millis(config.commands.inline_debounce_ms);
+    if !inline_queries.settle(q.from.id, &q.id, delay).await {
+        return Ok(());
+    }
+    let conversation = match command {
+        InlineCommand::Translate => {
+            chat_gpt::translate(&config.commands.translate_language, &input)
+        }
+        InlineCommand::VariableNamer => chat_gpt::naming_variable(&input),
+        InlineCommand::CheckGrammar => {
+            chat_gpt::check_grammar(&config.commands.grammar_language, &input)
+        }
+    };
+    let conversation = match conversation {
+        Ok(conversation) => conversation,
+        Err(e) => {
+            bot.answer_inline_query(q.id, [article(&q.query, "Usage", e.to_string())])
+                .cache_time(0)
+                .await?;
+            return Ok(());
+        }
+    };
+    // Inline queries come from no chat, so the user's private chat with the bot is limited.
+    if let Err(throttled) = rate_limiter.check(Some(q.from.id), ChatId(q.from.id.0 as i64)) {
+        let message = throttled.message();
+        bot.answer_inline_query(q.id, [article(&q.query, "Too many requests", message)])
+            .cache_time(0)
+            .await?;
+        return Ok(());
+    }
+
+    match chat_gpt::ask_chat_gpt(&chat_gpt, conversation).await {
+        Ok(completion) => {
+            record_user_usage(&storage, Some(q.from.id), &completion);
+            bot.answer_inline_query(
+                q.id,
+                [article(&q.query, command.title(), completion.content)],
+            )
+            .is_personal(true)
+            .await?;
+        }
+        Err(e) => {
+            error!("Cannot answer inline query from user {}: {e:#}", q.from.id);
+            let message = "Sorry, I couldn't get an answer, please try again.".to_string();
+            bot.answer_inline_query(q.id, [article(&q.query, "Cannot answer", message)])
+                .cache_time(0)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// A result that sends `text`, cut to fit in a message, to the chat when chosen, showing its
+/// beginning under `title`. Its id is derived from the query and the title, so that results of
+/// different queries don't share one.
+fn article(query: &str, title: &str, text: String) -> InlineQueryResult {
+    let mut hasher = DefaultHasher::new();
+    (query, title).hash(&mut hasher);
+    let mut length = 0;
+    let text = match text.char_indices().find(|(_, c)| {
+        length += c.len_utf16();
+        length > MAX_MESSAGE_LENGTH
+    }) {
+        Some((end, _)) => text[..end].to_string(),
+        None => text,
+    };
+    let description = text.chars().take(DESCRIPTION_CHARS).collect::<String>();
+    InlineQueryResult::Article(
+        InlineQueryResultArticle::new(
+            format!("{:016x}", hasher.finish()),
+            title,
+            InputMessageContent::Text(InputMessageContentText::new(text)),
+        )
+        .description(description),
+    )
+}
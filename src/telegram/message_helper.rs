@@ -1,13 +1,13 @@
+use teloxide::net::Download;
 use teloxide::prelude::*;
 use teloxide::types::{
     InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup, Message, ReplyMarkup,
 };
-use teloxide::Bot;
 
-use crate::telegram::startup::{Command, RolesRef};
+use crate::telegram::startup::{Command, EditRoleAction, RolesRef, ThrottledBot};
 
 pub async fn send_roles_using_inline_keyboard(
-    bot: Bot,
+    bot: ThrottledBot,
     msg: Message,
     roles: RolesRef,
     text: &str,
@@ -16,20 +16,19 @@ pub async fn send_roles_using_inline_keyboard(
     let roles = roles.lock().await;
 
     let buttons: Vec<Vec<InlineKeyboardButton>> = roles
-        .keys()
-        .collect::<Vec<&String>>()
+        .iter()
+        .collect::<Vec<_>>()
         .chunks(2)
         .map(|roles| {
             roles
                 .iter()
-                .map(|role| {
-                    let role = *role;
+                .map(|(name, role)| {
                     InlineKeyboardButton::new(
-                        role,
+                        role.display_name(name),
                         InlineKeyboardButtonKind::CallbackData(format!(
                             "{} {}",
                             serde_json::to_string(&command).unwrap(),
-                            role
+                            name
                         )),
                     )
                 })
@@ -46,3 +45,57 @@ pub async fn send_roles_using_inline_keyboard(
 
     Ok(())
 }
+
+pub fn edit_role_actions_keyboard() -> InlineKeyboardMarkup {
+    let button = |text: &str, action: EditRoleAction| {
+        InlineKeyboardButton::new(
+            text,
+            InlineKeyboardButtonKind::CallbackData(format!(
+                "{} {}",
+                serde_json::to_string(&Command::EditRole).unwrap(),
+                serde_json::to_string(&action).unwrap()
+            )),
+        )
+    };
+
+    InlineKeyboardMarkup::new(vec![
+        vec![
+            button("Replace prompt", EditRoleAction::Replace),
+            button("Append to prompt", EditRoleAction::Append),
+        ],
+        vec![button("Rename", EditRoleAction::Rename)],
+    ])
+}
+
+/// A Stop button for the message that shows an answer is being written.
+pub fn stop_answer_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::new(
+        "Stop",
+        InlineKeyboardButtonKind::CallbackData(format!(
+            "{} ",
+            serde_json::to_string(&Command::Stop).unwrap()
+        )),
+    )]])
+}
+
+/// A Summarize button for a message that is only a link. The link is read from the message the
+/// button's message replies to, since callback data is too short to hold most URLs.
+pub fn summarize_link_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::new(
+        "Summarize",
+        InlineKeyboardButtonKind::CallbackData(format!(
+            "{} ",
+            serde_json::to_string(&Command::Summarize(String::new())).unwrap()
+        )),
+    )]])
+}
+
+/// Bots can't download files bigger than this from Telegram.
+pub const MAX_DOWNLOAD_SIZE: u32 = 20 * 1024 * 1024;
+
+pub async fn download_file(bot: &ThrottledBot, file_id: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let file = bot.get_file(file_id).await?;
+    let mut contents = Vec::with_capacity(file.meta.size as usize);
+    bot.download_file(&file.path, &mut contents).await?;
+    Ok(contents)
+}
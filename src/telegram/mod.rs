@@ -1,5 +1,17 @@
+mod chat_queue;
+mod dialogue_storage;
+mod document_store;
+mod documents;
+mod inline_query;
 mod message_helper;
+mod prompt_tools;
+mod rate_limiter;
+mod role_transfer;
+mod roles_watcher;
+mod session_store;
 mod startup;
+mod voice;
+mod webhook;
 
+pub use prompt_tools::builtin_command_names;
 pub use startup::startup;
-pub use startup::Command;
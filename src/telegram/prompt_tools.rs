@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use teloxide::prelude::*;
+use teloxide::types::{BotCommand, Me};
+use teloxide::utils::command::BotCommands;
+
+use crate::chat_gpt;
+use crate::chat_gpt::{ChatGptRef, PromptTools};
+use crate::storages::StorageRef;
+use crate::telegram::rate_limiter::RateLimiterRef;
+use crate::telegram::startup::{
+    check_rate_limits, send_completion, send_usage_error, Command, HandlerResult, ThrottledBot,
+};
+
+pub type PromptToolsRef = Arc<PromptTools>;
+
+/// A message calling a prompt tool, as `/commit --conventional <diff>`.
+#[derive(Clone)]
+pub struct ToolCall {
+    name: String,
+    input: String,
+}
+
+/// Names of the commands built into the bot, which prompt tools can't take.
+pub fn builtin_command_names() -> Vec<String> {
+    Command::bot_commands()
+        .into_iter()
+        .map(|command| command.command.trim_start_matches('/').to_string())
+        .collect()
+}
+
+/// The built-in commands followed by the prompt tools, for the bot's command menu.
+pub fn bot_commands(tools: &PromptTools) -> Vec<BotCommand> {
+    let mut commands = Command::bot_commands();
+    commands.extend(
+        tools
+            .iter()
+            .map(|(name, tool)| BotCommand::new(format!("/{name}"), tool.description.trim())),
+    );
+    commands
+}
+
+/// The prompt tool `msg` calls, if it is one. Like other commands, a tool can be addressed to
+/// the bot by name, as in `/commit@my_bot`.
+pub fn parse_tool_call(msg: Message, me: Me, tools: PromptToolsRef) -> Option<ToolCall> {
+    tool_call(msg.text()?, me.username(), &tools)
+}
+
+fn tool_call(text: &str, bot_username: &str, tools: &PromptTools) -> Option<ToolCall> {
+    let text = text.strip_prefix('/')?;
+    let (command, input) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let name = match command.split_once('@') {
+        Some((name, bot_name)) if bot_name.eq_ignore_ascii_case(bot_username) => name,
+        Some(_) => return None,
+        None => command,
+    };
+    tools.contains_key(name).then(|| ToolCall {
+        name: name.to_string(),
+        input: input.to_string(),
+    })
+}
+
+pub async fn run_prompt_tool(
+    bot: ThrottledBot,
+    msg: Message,
+    chat_gpt: ChatGptRef,
+    storage: StorageRef,
+    rate_limiter: RateLimiterRef,
+    tools: PromptToolsRef,
+    call: ToolCall,
+) -> HandlerResult {
+    let tool = &tools[&call.name];
+    let conversation = match chat_gpt::prompt_tool_conversation(tool, &call.input) {
+        Ok(conversation) => conversation,
+        Err(e) => return send_usage_error(&bot, &msg, &e).await,
+    };
+    if !check_rate_limits(&bot, &msg, &rate_limiter).await? {
+        return Ok(());
+    }
+    bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::Typing)
+        .await?;
+    let result = chat_gpt::run_prompt_tool(&chat_gpt, tool, conversation).await;
+    send_completion(&bot, &msg, &storage, result).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tools() -> PromptTools {
+        serde_yaml::from_str(
+            "commit:\n  description: Write a commit message\n  system: Write a commit message.\n",
+        )
+        .unwrap()
+    }
+
+    fn call(text: &str) -> Option<(String, String)> {
+        tool_call(text, "my_bot", &tools()).map(|call| (call.name, call.input))
+    }
+
+    #[test]
+    fn parses_tool_calls() {
+        assert_eq!(
+            call("/commit -c fix typo"),
+            Some(("commit".to_string(), "-c fix typo".to_string()))
+        );
+        assert_eq!(call("/commit"), Some(("commit".to_string(), String::new())));
+        assert_eq!(call("/regex digits"), None);
+        assert_eq!(call("commit"), None);
+    }
+
+    #[test]
+    fn accepts_calls_addressed_to_this_bot_only() {
+        assert_eq!(
+            call("/commit@my_bot fix typo"),
+            Some(("commit".to_string(), "fix typo".to_string()))
+        );
+        assert_eq!(
+            call("/commit@My_Bot fix typo"),
+            Some(("commit".to_string(), "fix typo".to_string()))
+        );
+        assert_eq!(call("/commit@other_bot fix typo"), None);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use teloxide::types::{ChatId, UserId};
+
+use crate::config::{RateLimit, RateLimitsConfig};
+
+pub type RateLimiterRef = Arc<RateLimiter>;
+
+/// Full buckets are forgotten once there are more than this many buckets of a kind.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+/// Limits requests to ChatGPT per user, per chat and for the whole bot.
+pub struct RateLimiter {
+    config: RateLimitsConfig,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    users: HashMap<UserId, TokenBucket>,
+    chats: HashMap<ChatId, TokenBucket>,
+    global: Option<TokenBucket>,
+}
+
+/// The limit that was reached, and how long until a request is allowed again.
+pub struct Throttled {
+    pub scope: Scope,
+    pub retry_after: Duration,
+}
+
+#[derive(Clone, Copy)]
+pub enum Scope {
+    User,
+    Chat,
+    Global,
+}
+
+impl Throttled {
+    pub fn message(&self) -> String {
+        let reason = match self.scope {
+            Scope::User => "You are sending requests too fast.",
+            Scope::Chat => "This chat is sending requests too fast.",
+            Scope::Global => "The bot is busy right now.",
+        };
+        let seconds = self.retry_after.as_secs_f64().ceil().max(1.0);
+        format!("{reason} Please try again in {seconds} seconds.")
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitsConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Takes a request from each limit that applies. When one of them has no request left,
+    /// nothing is taken.
+    pub fn check(&self, user_id: Option<UserId>, chat_id: ChatId) -> Result<(), Throttled> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets {
+            users,
+            chats,
+            global,
+        } = &mut *buckets;
+
+        let mut taken = vec![];
+        if let (Some(limit), Some(user_id)) = (self.config.user, user_id) {
+            taken.push((Scope::User, limit, bucket(users, user_id, limit, now)));
+        }
+        if let Some(limit) = self.config.chat {
+            taken.push((Scope::Chat, limit, bucket(chats, chat_id, limit, now)));
+        }
+        if let Some(limit) = self.config.global {
+            let bucket = global.get_or_insert_with(|| TokenBucket::full(limit, now));
+            taken.push((Scope::Global, limit, bucket));
+        }
+
+        for (scope, limit, bucket) in taken.iter_mut() {
+            bucket.refill(*limit, now);
+            if bucket.tokens < 1.0 {
+                let retry_after = (1.0 - bucket.tokens) * 60.0 / limit.per_minute;
+                return Err(Throttled {
+                    scope: *scope,
+                    retry_after: Duration::from_secs_f64(retry_after),
+                });
+            }
+        }
+        for (_, _, bucket) in taken {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+fn bucket<K: Eq + Hash>(
+    buckets: &mut HashMap<K, TokenBucket>,
+    key: K,
+    limit: RateLimit,
+    now: Instant,
+) -> &mut TokenBucket {
+    if buckets.len() > MAX_IDLE_BUCKETS {
+        buckets.retain(|_, bucket| !bucket.is_full(limit, now));
+    }
+    buckets
+        .entry(key)
+        .or_insert_with(|| TokenBucket::full(limit, now))
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_minute / 60.0).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    fn is_full(&self, limit: RateLimit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.per_minute / 60.0 >= limit.burst as f64
+    }
+}
@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile};
+
+use crate::storages;
+use crate::storages::{FileFormat, Roles, StorageRef};
+use crate::telegram::message_helper::download_file;
+use crate::telegram::startup::{
+    save_roles, BotDialogue, Command, HandlerResult, RolesRef, State, ThrottledBot,
+};
+
+/// Role files are small, anything bigger is most likely the wrong file.
+const MAX_IMPORT_FILE_SIZE: u32 = 1024 * 1024;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum ImportRolesAction {
+    Merge,
+    AddOnly,
+    Cancel,
+}
+
+/// How an imported role set differs from the stored one.
+#[derive(Default)]
+struct RolesDiff {
+    added: Vec<String>,
+    changed: Vec<String>,
+    /// Imported roles whose name only differs from an existing role by case.
+    differing_case: Vec<String>,
+    unchanged: usize,
+}
+
+impl RolesDiff {
+    fn new(current: &Roles, imported: &Roles) -> RolesDiff {
+        let mut diff = RolesDiff::default();
+        for (name, role) in imported {
+            match current.get(name) {
+                Some(existing) if existing == role => diff.unchanged += 1,
+                Some(_) => diff.changed.push(name.clone()),
+                None if current
+                    .keys()
+                    .any(|n| n.to_lowercase() == name.to_lowercase()) =>
+                {
+                    diff.differing_case.push(name.clone())
+                }
+                None => diff.added.push(name.clone()),
+            }
+        }
+        diff.added.sort();
+        diff.changed.sort();
+        diff.differing_case.sort();
+        diff
+    }
+
+    fn describe(&self) -> String {
+        let list = |names: &[String]| {
+            if names.is_empty() {
+                "-".to_string()
+            } else {
+                names.join(", ")
+            }
+        };
+        format!(
+            "Added ({}): {}\nChanged ({}): {}\nNames differing only by case ({}): {}\nUnchanged: {}",
+            self.added.len(),
+            list(&self.added),
+            self.changed.len(),
+            list(&self.changed),
+            self.differing_case.len(),
+            list(&self.differing_case),
+            self.unchanged,
+        )
+    }
+}
+
+pub async fn export_roles(
+    bot: ThrottledBot,
+    msg: Message,
+    roles: RolesRef,
+    format: String,
+) -> HandlerResult {
+    let format = match format.trim().to_lowercase().as_str() {
+        "" | "yaml" | "yml" => FileFormat::Yaml,
+        "json" => FileFormat::Json,
+        _ => {
+            bot.send_message(msg.chat.id, "Supported formats are yaml and json.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let contents = storages::serialize_roles(&*roles.lock().await, format)?;
+    bot.send_document(
+        msg.chat.id,
+        InputFile::memory(contents.into_bytes()).file_name(format!("roles.{}", format.extension())),
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn start_import_roles(
+    bot: ThrottledBot,
+    msg: Message,
+    dialogue: BotDialogue,
+) -> HandlerResult {
+    bot.send_message(
+        msg.chat.id,
+        "Please send the roles file to import, as a YAML or JSON document.",
+    )
+    .await?;
+    dialogue.update(State::ReceiveImportRolesFile).await?;
+    Ok(())
+}
+
+pub async fn receive_import_roles_file(
+    bot: ThrottledBot,
+    msg: Message,
+    roles: RolesRef,
+    dialogue: BotDialogue,
+) -> HandlerResult {
+    let Some(document) = msg.document() else {
+        bot.send_message(msg.chat.id, "Please send the roles as a file.")
+            .await?;
+        return Ok(());
+    };
+    if document.file.size > MAX_IMPORT_FILE_SIZE {
+        bot.send_message(msg.chat.id, "The file is too large to be a roles file.")
+            .await?;
+        return Ok(());
+    }
+
+    let format = FileFormat::from_file_name(document.file_name.as_deref().unwrap_or_default());
+    let contents = download_file(&bot, &document.file.id).await?;
+    let imported = String::from_utf8(contents)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| storages::deserialize_roles(&contents, format))
+        .map(|(imported, _)| imported)
+        .and_then(|imported| storages::validate_roles(&imported).map(|_| imported));
+    let imported = match imported {
+        Ok(imported) => imported,
+        Err(e) => {
+            bot.send_message(
+                msg.chat.id,
+                format!("Cannot import this file: {e:#}\nPlease send a corrected file."),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let diff = RolesDiff::new(&*roles.lock().await, &imported);
+    if diff.added.is_empty() && diff.changed.is_empty() {
+        dialogue.update(State::None).await?;
+        let text = if diff.differing_case.is_empty() {
+            "Nothing to import, all roles are already up to date.".to_string()
+        } else {
+            format!(
+                "Nothing to import.\n{}\nRename the roles whose names differ only by case to import them.",
+                diff.describe()
+            )
+        };
+        bot.send_message(msg.chat.id, text).await?;
+        return Ok(());
+    }
+
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "{}\n\nRoles whose names differ only by case will be skipped. Merge the roles into storage?",
+            diff.describe()
+        ),
+    )
+    .reply_markup(import_roles_actions_keyboard(!diff.changed.is_empty()))
+    .await?;
+    dialogue
+        .update(State::ConfirmImportRoles { roles: imported })
+        .await?;
+    Ok(())
+}
+
+pub async fn do_import_roles(
+    bot: ThrottledBot,
+    msg: Message,
+    roles: RolesRef,
+    storage: StorageRef,
+    dialogue: BotDialogue,
+    callback_data: &str,
+) -> HandlerResult {
+    let Some(State::ConfirmImportRoles { roles: imported }) = dialogue.get().await? else {
+        bot.edit_message_text(msg.chat.id, msg.id, "This import has expired.")
+            .await?;
+        return Ok(());
+    };
+    let Ok(action) = serde_json::from_str::<ImportRolesAction>(callback_data) else {
+        return Ok(());
+    };
+    dialogue.update(State::None).await?;
+
+    let mut roles = roles.lock().await;
+    let diff = RolesDiff::new(&roles, &imported);
+    let names = match action {
+        ImportRolesAction::Merge => diff.added.iter().chain(diff.changed.iter()).collect(),
+        ImportRolesAction::AddOnly => diff.added.iter().collect::<Vec<_>>(),
+        ImportRolesAction::Cancel => {
+            bot.edit_message_text(msg.chat.id, msg.id, "Import cancelled.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let mut updated = roles.clone();
+    for name in &names {
+        updated.insert(name.to_string(), imported[*name].clone());
+    }
+    if !save_roles(&bot, msg.chat.id, &storage, &mut roles, updated).await? {
+        return Ok(());
+    }
+    bot.edit_message_text(
+        msg.chat.id,
+        msg.id,
+        format!("Imported {} roles.", names.len()),
+    )
+    .await?;
+    Ok(())
+}
+
+fn import_roles_actions_keyboard(has_changes: bool) -> InlineKeyboardMarkup {
+    let button = |text: &str, action: ImportRolesAction| {
+        InlineKeyboardButton::callback(
+            text,
+            format!(
+                "{} {}",
+                serde_json::to_string(&Command::ImportRoles).unwrap(),
+                serde_json::to_string(&action).unwrap()
+            ),
+        )
+    };
+
+    let mut buttons = vec![button("Merge", ImportRolesAction::Merge)];
+    if has_changes {
+        buttons.push(button("Only add new roles", ImportRolesAction::AddOnly));
+    }
+    buttons.push(button("Cancel", ImportRolesAction::Cancel));
+    InlineKeyboardMarkup::new(vec![buttons])
+}
@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{error, info};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use teloxide::prelude::*;
+
+use crate::storages;
+use crate::storages::{Session, StorageRef};
+use crate::telegram::session_store::SessionsRef;
+use crate::telegram::startup::{new_session, RolesRef, ThrottledBot};
+
+/// Editors usually save in several steps, so wait for the file to settle before reloading.
+const RELOAD_DELAY: Duration = Duration::from_millis(300);
+
+/// Reloads the roles whenever the roles file changes on disk.
+///
+/// The returned watcher stops watching when dropped.
+pub fn watch_roles(
+    bot: ThrottledBot,
+    admin_chat_id: Option<ChatId>,
+    roles_file: PathBuf,
+    storage: StorageRef,
+    roles: RolesRef,
+    sessions: SessionsRef,
+) -> Result<RecommendedWatcher, anyhow::Error> {
+    let file_name = roles_file.file_name().map(ToOwned::to_owned);
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+    // The directory is watched rather than the file, as editors often replace the file on save.
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event)
+                if event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == file_name.as_deref()) =>
+            {
+                let _ = tx.send(());
+            }
+            Ok(_) => {}
+            Err(e) => error!("Roles watcher error: {e}"),
+        })?;
+    let dir = match roles_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(RELOAD_DELAY).await;
+            while rx.try_recv().is_ok() {}
+
+            let report = reload_roles(&storage, &roles, &sessions).await;
+            if let Some(report) = report {
+                info!("{report}");
+                if let Some(admin_chat_id) = admin_chat_id {
+                    if let Err(e) = bot.send_message(admin_chat_id, report).await {
+                        error!("Cannot report roles reload to admin: {e}");
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(watcher)
+}
+
+/// Swaps the stored roles for the ones on disk and returns a message worth reporting, if any.
+async fn reload_roles(
+    storage: &StorageRef,
+    roles: &RolesRef,
+    sessions: &SessionsRef,
+) -> Option<String> {
+    let reloaded = match storage
+        .reload_roles()
+        .and_then(|reloaded| storages::validate_roles(&reloaded).map(|_| reloaded))
+    {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            return Some(format!(
+                "Roles file not reloaded, keeping the current roles: {e:#}"
+            ))
+        }
+    };
+
+    let mut roles = roles.lock().await;
+    // The bot rewrites the file itself whenever roles are changed through commands.
+    if *roles == reloaded {
+        return None;
+    }
+    let previous = std::mem::replace(&mut *roles, reloaded);
+
+    let mut reset_sessions = 0;
+    let mut sessions = sessions.lock().await;
+    for session in sessions.values_mut() {
+        match roles.get(&session.role) {
+            Some(role) => {
+                // The session starts with the role's system prompt and examples, which are
+                // swapped for the reloaded ones, keeping the conversation that follows them.
+                let prefix_len = previous
+                    .get(&session.role)
+                    .map_or(1, |role| 1 + 2 * role.examples.len())
+                    .min(session.history.len());
+                let mut history = role.initial_conversation(&session.variables);
+                history.extend(session.history.drain(prefix_len..));
+                session.history = history;
+            }
+            None => {
+                *session = Session {
+                    voice: session.voice,
+                    generation: session.generation + 1,
+                    ..new_session(&roles)
+                };
+                reset_sessions += 1;
+            }
+        }
+    }
+    let chat_ids = sessions.keys().copied().collect::<Vec<_>>();
+    for chat_id in chat_ids {
+        sessions.save(chat_id);
+    }
+    drop(sessions);
+
+    let mut report = format!("Roles reloaded, {} roles available.", roles.len());
+    if reset_sessions > 0 {
+        report.push_str(&format!(
+            " {reset_sessions} sessions used a removed role and were switched to the default role."
+        ));
+    }
+    Some(report)
+}
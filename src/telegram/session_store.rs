@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use log::error;
+use teloxide::types::ChatId;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::storages::{Session, StorageRef};
+
+pub type SessionsRef = Arc<SessionStore>;
+
+/// The chats' sessions, kept in memory and saved to storage in the background, so that one
+/// chat's disk writes don't hold up the others.
+pub struct SessionStore {
+    storage: StorageRef,
+    sessions: Mutex<HashMap<ChatId, Session>>,
+    saves: std::sync::Mutex<HashMap<ChatId, Arc<ChatSaves>>>,
+}
+
+/// Orders the saves of a chat's session, which run on the blocking thread pool.
+#[derive(Default)]
+struct ChatSaves {
+    /// Number of the latest save asked for.
+    requested: AtomicU64,
+    /// Held while the session is written.
+    writing: std::sync::Mutex<()>,
+}
+
+impl SessionStore {
+    pub fn new(storage: StorageRef, sessions: HashMap<ChatId, Session>) -> SessionStore {
+        SessionStore {
+            storage,
+            sessions: Mutex::new(sessions),
+            saves: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn lock(&self) -> SessionsGuard<'_> {
+        SessionsGuard {
+            store: self,
+            sessions: self.sessions.lock().await,
+        }
+    }
+
+    /// Writes a copy of the session taken while the sessions are locked. A save that finds a
+    /// newer one asked for in the meantime leaves the writing to it, so an older copy never
+    /// overwrites a newer one.
+    fn save(&self, chat_id: ChatId, session: Session) {
+        let saves = self
+            .saves
+            .lock()
+            .unwrap()
+            .entry(chat_id)
+            .or_default()
+            .clone();
+        let number = saves.requested.fetch_add(1, Ordering::SeqCst) + 1;
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || {
+            let _writing = saves.writing.lock().unwrap_or_else(|e| e.into_inner());
+            if saves.requested.load(Ordering::SeqCst) != number {
+                return;
+            }
+            if let Err(e) = storage.save_session(chat_id.0, &session) {
+                error!("Cannot save session of chat {chat_id}: {e:#}");
+            }
+        });
+    }
+}
+
+/// The locked sessions.
+pub struct SessionsGuard<'a> {
+    store: &'a SessionStore,
+    sessions: MutexGuard<'a, HashMap<ChatId, Session>>,
+}
+
+impl SessionsGuard<'_> {
+    /// Saves the chat's session as it is now, if it has one.
+    pub fn save(&self, chat_id: ChatId) {
+        if let Some(session) = self.sessions.get(&chat_id) {
+            self.store.save(chat_id, session.clone());
+        }
+    }
+}
+
+impl Deref for SessionsGuard<'_> {
+    type Target = HashMap<ChatId, Session>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.sessions
+    }
+}
+
+impl DerefMut for SessionsGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.sessions
+    }
+}
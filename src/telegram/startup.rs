@@ -1,23 +1,59 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::info;
-use openai_chatgpt_api::ChatGptChatFormat;
+use anyhow::Context;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
+use teloxide::adaptors::throttle::Limits;
+use teloxide::adaptors::Throttle;
 use teloxide::dispatching::dialogue;
-use teloxide::dispatching::dialogue::InMemStorage;
+use teloxide::dispatching::dialogue::ErasedStorage;
 use teloxide::dptree::case;
-use teloxide::types::ParseMode;
+use teloxide::types::{ParseMode, User};
 use teloxide::{payloads::SendMessageSetters, prelude::*, utils::command::BotCommands};
 use tokio::sync::Mutex;
 
-use crate::chat_gpt::ask_chat_gpt;
-use crate::storages::{Role, Roles};
-use crate::telegram::message_helper::send_roles_using_inline_keyboard;
+use crate::chat_gpt;
+use crate::chat_gpt::{
+    ask_chat_gpt, load_prompt_tools, ChatGptClient, ChatGptRef, ChatMessage, Completion,
+    PromptTools,
+};
+use crate::config::{Config, ConfigRef, TelegramConfig};
+use crate::storages::{validate_role_name, Role, Roles, Session, StorageRef, Usage, UsersSettings};
+use crate::telegram::chat_queue::{ChatQueue, ChatQueueRef};
+use crate::telegram::dialogue_storage::open_dialogue_storage;
+use crate::telegram::document_store::{DocumentStore, DocumentStoreRef};
+use crate::telegram::documents::{
+    attachment, cited_sources, find_passages, manage_documents, passages_prompt, read_document,
+    store_document,
+};
+use crate::telegram::inline_query::{inline_query_handler, InlineQueries, InlineQueriesRef};
+use crate::telegram::message_helper::{
+    download_file, edit_role_actions_keyboard, send_roles_using_inline_keyboard,
+    stop_answer_keyboard, summarize_link_keyboard,
+};
+use crate::telegram::prompt_tools::{
+    bot_commands, builtin_command_names, parse_tool_call, run_prompt_tool, PromptToolsRef,
+};
+use crate::telegram::rate_limiter::{RateLimiter, RateLimiterRef};
+use crate::telegram::role_transfer::{
+    do_import_roles, export_roles, receive_import_roles_file, start_import_roles,
+};
+use crate::telegram::roles_watcher::watch_roles;
+use crate::telegram::session_store::{SessionStore, SessionsRef};
+use crate::telegram::voice::{
+    answer_speech, is_voice_message, send_spoken_answer, set_voice, transcribe_voice,
+};
+use crate::telegram::webhook::webhook_listener;
+use crate::utils::options::{describe_options, UsageError};
 use crate::utils::telegram_utils::escape_markdown_v2_reversed_chars;
-use crate::{chat_gpt, storages};
+use crate::utils::template;
+use crate::utils::template::Variables;
 
-type NewRoleDialogue = Dialogue<State, InMemStorage<State>>;
-type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+/// The bot, keeping its requests within Telegram's rate limits.
+pub type ThrottledBot = Throttle<Bot>;
+pub type BotDialogue = Dialogue<State, ErasedStorage<State>>;
+pub type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
 
 #[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
 pub enum State {
@@ -27,6 +63,32 @@ pub enum State {
     ReceiveNewRoleSystem {
         role_name: String,
     },
+    ReceiveEditRoleAction {
+        role_name: String,
+    },
+    ReceiveEditRoleSystem {
+        role_name: String,
+        action: EditRoleAction,
+    },
+    ReceiveEditRoleNewName {
+        role_name: String,
+    },
+    ReceiveRoleVariable {
+        role_name: String,
+        variable: String,
+        values: Variables,
+    },
+    ReceiveImportRolesFile,
+    ConfirmImportRoles {
+        roles: Roles,
+    },
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum EditRoleAction {
+    Replace,
+    Append,
+    Rename,
 }
 
 #[derive(BotCommands, Clone, Serialize, Deserialize)]
@@ -35,16 +97,28 @@ pub enum State {
     description = "These commands are supported:"
 )]
 pub enum Command {
+    #[command(description = "Show the commands and their options")]
+    Help,
     #[command(description = "Clear conversation history and start a new session")]
     Clear,
+    #[command(description = "Cancel the current operation")]
+    Cancel,
+    #[command(description = "Stop the answer being written")]
+    Stop,
     #[command(description = "List all roles")]
     ListRoles,
     #[command(description = "Add a role")]
     NewRole,
+    #[command(description = "Edit a role's system prompt or name")]
+    EditRole,
     #[command(description = "Delete a role")]
     DeleteRole,
     #[command(description = "Switch to another role")]
     SwitchRole,
+    #[command(description = "Export all roles as a file, in yaml (default) or json")]
+    ExportRoles(String),
+    #[command(description = "Import roles from a YAML or JSON file")]
+    ImportRoles,
     #[command(
         rename = "trans",
         description = "Translate given text to specify language"
@@ -60,91 +134,255 @@ pub enum Command {
         description = "Check the grammar of the sentence and provide suggestions for improvement."
     )]
     CheckGrammar(String),
+    #[command(description = "Summarize a web page or a text: /summarize <url or text>")]
+    Summarize(String),
+    #[command(
+        rename = "setvar",
+        description = "Set a variable used by role prompt templates, e.g. /setvar language Japanese"
+    )]
+    SetVariable(String),
+    #[command(description = "Send answers in this chat as voice messages: /voice on|off")]
+    Voice(String),
+    #[command(description = "List this chat's documents, or remove one: /docs remove <name>")]
+    Docs(String),
 }
 
-type ConversationHistoryRef = Arc<Mutex<Vec<ChatGptChatFormat>>>;
+const DEFAULT_ROLE: &str = "assistant";
+
 pub type RolesRef = Arc<Mutex<Roles>>;
+type UserSettingsRef = Arc<Mutex<UsersSettings>>;
 
+/// What answering chat messages needs, injected as one dependency because handlers can't take
+/// more than nine.
 #[derive(Clone)]
-struct Settings {
-    open_ai_api_key: String,
+struct Answerer {
+    sessions: SessionsRef,
+    roles: RolesRef,
+    storage: StorageRef,
+    config: ConfigRef,
+    chat_gpt: ChatGptRef,
+    queue: ChatQueueRef,
+    rate_limiter: RateLimiterRef,
+    document_store: DocumentStoreRef,
 }
 
-impl Settings {
-    fn from_env() -> Settings {
-        Settings {
-            open_ai_api_key: std::env::var("OPEN_AI_API_KEY").expect("OPEN_AI_API_KEY must be set"),
-        }
+/// The assistant role when there is one, otherwise the first role by name. New sessions can't
+/// ask for variables, so only roles that need none besides the built-in ones are considered.
+pub fn get_default_role(roles: &Roles) -> (String, Role) {
+    let variables = builtin_variables(None);
+    let usable = |role: &Role| {
+        role.placeholders()
+            .iter()
+            .all(|name| variables.contains_key(name))
+    };
+    let default = roles
+        .get_key_value(DEFAULT_ROLE)
+        .filter(|(_, role)| usable(role))
+        .or_else(|| {
+            roles
+                .iter()
+                .filter(|(_, role)| usable(role))
+                .min_by_key(|(name, _)| *name)
+        });
+    if let Some((role_name, role)) = default {
+        (role_name.clone(), role.clone())
+    } else {
+        (
+            DEFAULT_ROLE.to_string(),
+            Role::new("you are a helpful assistant.".to_string()),
+        )
     }
 }
 
-pub fn get_default_role(roles: &Roles) -> (&str, &str) {
-    let system;
-    let _role;
-    if let Some((role_name, role)) = roles.iter().next() {
-        system = role.system.as_str();
-        _role = role_name.as_str();
-    } else {
-        system = "you are a helpful assistant.";
-        _role = "assistant";
+/// A session with the default role, for chats that haven't talked to the bot yet.
+pub fn new_session(roles: &Roles) -> Session {
+    let (role_name, role) = get_default_role(roles);
+    let variables = builtin_variables(None);
+    Session {
+        role: role_name,
+        history: role.initial_conversation(&variables),
+        variables,
+        attachments: vec![],
+        voice: None,
+        generation: 0,
     }
-    (_role, system)
 }
 
-pub async fn startup() -> Result<(), anyhow::Error> {
-    let settings = Settings::from_env();
-    let bot = Bot::from_env();
-    bot.set_my_commands(Command::bot_commands()).await?;
+/// A bot whose requests go through `config.proxy`, when one is set.
+fn new_bot(config: &TelegramConfig) -> Result<Bot, anyhow::Error> {
+    let mut builder = teloxide::net::default_reqwest_settings();
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy).context("Invalid Telegram proxy")?);
+    }
+    let client = builder
+        .build()
+        .context("Cannot create the Telegram HTTP client")?;
+    Ok(Bot::with_client(&config.token, client))
+}
 
-    let saved_roles = storages::get_roles()?;
+pub async fn startup(storage: StorageRef, config: Config) -> Result<(), anyhow::Error> {
+    let config: ConfigRef = Arc::new(config);
+    let bot = new_bot(&config.telegram)?.throttle(Limits::default());
+    let chat_gpt: ChatGptRef = Arc::new(ChatGptClient::new(&config)?);
+    let queue: ChatQueueRef = Arc::new(ChatQueue::new(Duration::from_millis(
+        config.queue.debounce_ms,
+    )));
+    let rate_limiter: RateLimiterRef = Arc::new(RateLimiter::new(config.rate_limits.clone()));
+    let inline_queries: InlineQueriesRef = Arc::new(InlineQueries::default());
+    let prompt_tools: PromptToolsRef = Arc::new(match &config.commands.tools_file {
+        Some(path) => load_prompt_tools(path, &builtin_command_names())?,
+        None => PromptTools::new(),
+    });
+    bot.set_my_commands(bot_commands(&prompt_tools)).await?;
 
-    let ignore_update = |_upd| Box::pin(async {});
+    let saved_roles = storage.load_roles()?;
+    let user_settings = Arc::new(Mutex::new(storage.load_user_settings()?));
+    let sessions: SessionsRef = Arc::new(SessionStore::new(
+        storage.clone(),
+        storage
+            .load_sessions()?
+            .into_iter()
+            .map(|(chat_id, session)| (ChatId(chat_id), session))
+            .collect(),
+    ));
 
-    let (current_role, default_system) = get_default_role(&saved_roles);
+    let document_store: DocumentStoreRef = Arc::new(DocumentStore::new(
+        storage.clone(),
+        storage.load_documents()?,
+    ));
 
-    let chat_gpt_system = ChatGptChatFormat::new_system(default_system);
-    let conversation_history = Arc::new(Mutex::new(vec![chat_gpt_system]));
-    let current_role = Arc::new(Mutex::new(current_role.to_string()));
+    let dialogue_storage = open_dialogue_storage(&config.storage).await?;
+
+    let ignore_update = |_upd| Box::pin(async {});
 
     let saved_roles_ref = Arc::new(Mutex::new(saved_roles));
 
-    let handler = dialogue::enter::<Update, InMemStorage<State>, State, _>()
+    let _roles_watcher = match storage.roles_file() {
+        Some(roles_file) => Some(watch_roles(
+            bot.clone(),
+            config.telegram.admin_chat_id.map(ChatId),
+            roles_file.to_path_buf(),
+            storage.clone(),
+            saved_roles_ref.clone(),
+            sessions.clone(),
+        )?),
+        None => None,
+    };
+
+    let handler = dptree::entry()
+        .filter(|upd: Update, config: ConfigRef| {
+            let allowed = config.access.is_allowed(
+                upd.user().map(|user| user.id.0),
+                upd.chat().map(|chat| chat.id.0),
+            );
+            if !allowed {
+                info!("Ignoring update from a user or chat that is not allowed");
+            }
+            allowed
+        })
+        // Inline queries come from no chat, so they have no dialogue.
+        .branch(Update::filter_inline_query().endpoint(inline_query_handler))
+        .chain(dialogue::enter::<Update, ErasedStorage<State>, State, _>())
         .branch(
             Update::filter_message()
+                // Cancelling and stopping have to work from any state, so they go before the
+                // state branches.
+                .branch(
+                    dptree::entry()
+                        .filter_command::<Command>()
+                        .branch(case![Command::Cancel].endpoint(cancel))
+                        .branch(case![Command::Stop].endpoint(stop_answer)),
+                )
                 .branch(case![State::ReceiveNewRoleName].endpoint(receive_new_role_name))
                 .branch(
                     case![State::ReceiveNewRoleSystem { role_name }]
                         .endpoint(receive_new_role_system),
                 )
+                .branch(
+                    case![State::ReceiveEditRoleSystem { role_name, action }]
+                        .endpoint(receive_edit_role_system),
+                )
+                .branch(
+                    case![State::ReceiveEditRoleNewName { role_name }]
+                        .endpoint(receive_edit_role_new_name),
+                )
+                .branch(
+                    case![State::ReceiveRoleVariable {
+                        role_name,
+                        variable,
+                        values
+                    }]
+                    .endpoint(receive_role_variable),
+                )
+                .branch(case![State::ReceiveImportRolesFile].endpoint(receive_import_roles_file))
                 .branch(
                     dptree::entry()
                         .filter_command::<Command>()
+                        .branch(case![Command::Translate(user_input)].endpoint(translate))
+                        .branch(case![Command::VariableNamer(scene)].endpoint(naming_variable))
+                        .branch(case![Command::CheckGrammar(sentence)].endpoint(check_grammar))
+                        .branch(case![Command::Summarize(input)].endpoint(summarize))
+                        .branch(case![Command::Help].endpoint(help))
+                        .branch(case![Command::Docs(input)].endpoint(manage_documents))
                         .branch(dptree::endpoint(command_handler)),
                 )
+                .branch(dptree::filter_map(parse_tool_call).endpoint(run_prompt_tool))
                 .branch(dptree::endpoint(message_handler)),
         )
         .branch(Update::filter_callback_query().endpoint(callback_handler));
 
-    Dispatcher::builder(bot, handler)
+    let answerer = Answerer {
+        sessions: sessions.clone(),
+        roles: saved_roles_ref.clone(),
+        storage: storage.clone(),
+        config: config.clone(),
+        chat_gpt: chat_gpt.clone(),
+        queue: queue.clone(),
+        rate_limiter: rate_limiter.clone(),
+        document_store: document_store.clone(),
+    };
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
         .dependencies(dptree::deps![
-            conversation_history,
-            settings,
+            sessions,
+            config.clone(),
+            chat_gpt,
+            queue,
+            rate_limiter,
+            document_store,
+            inline_queries,
+            prompt_tools,
+            answerer,
             saved_roles_ref,
-            current_role,
-            InMemStorage::<State>::new()
+            storage,
+            user_settings,
+            dialogue_storage
         ])
         .default_handler(ignore_update)
         .error_handler(LoggingErrorHandler::with_custom_text(
             "An error has occurred in the dispatcher",
         ))
         .enable_ctrlc_handler()
-        .build()
-        .dispatch()
-        .await;
+        .build();
+    match config.webhook.url.clone() {
+        Some(url) => {
+            let listener = webhook_listener(bot, &config.webhook, url).await?;
+            dispatcher
+                .dispatch_with_listener(
+                    listener,
+                    LoggingErrorHandler::with_custom_text("An error from the webhook listener"),
+                )
+                .await
+        }
+        None => dispatcher.dispatch().await,
+    }
     Ok(())
 }
 
-async fn switch_role(bot: Bot, msg: Message, roles: RolesRef) -> Result<(), anyhow::Error> {
+async fn switch_role(
+    bot: ThrottledBot,
+    msg: Message,
+    roles: RolesRef,
+) -> Result<(), anyhow::Error> {
     send_roles_using_inline_keyboard(
         bot,
         msg,
@@ -156,27 +394,42 @@ async fn switch_role(bot: Bot, msg: Message, roles: RolesRef) -> Result<(), anyh
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn do_switch_role(
-    bot: Bot,
+    bot: ThrottledBot,
     msg: Message,
-    conversation_history: ConversationHistoryRef,
+    user: &User,
+    sessions: SessionsRef,
     roles: RolesRef,
-    current_role: Arc<Mutex<String>>,
+    user_settings: UserSettingsRef,
+    dialogue: BotDialogue,
+    queue: ChatQueueRef,
     role_name: &str,
 ) -> Result<(), anyhow::Error> {
-    let roles = roles.lock().await;
-    if let Some(role) = roles.get(role_name) {
-        let mut conversation_history = conversation_history.lock().await;
-        conversation_history.clear();
-
-        let mut current_role = current_role.lock().await;
-        if *current_role == role_name {
-            bot.edit_message_text(msg.chat.id, msg.id, "I'm already this role.")
-                .await?;
-        } else {
-            *current_role = role_name.to_string();
+    let current_role = sessions
+        .lock()
+        .await
+        .get(&msg.chat.id)
+        .map(|session| session.role.clone());
+    if current_role.as_deref() == Some(role_name) {
+        bot.edit_message_text(msg.chat.id, msg.id, "I'm already this role.")
+            .await?;
+        return Ok(());
+    }
 
-            conversation_history.push(ChatGptChatFormat::new_system(&role.system));
+    let switch = switch_to_role(
+        msg.chat.id,
+        role_name,
+        Some(user),
+        Variables::new(),
+        &roles,
+        &sessions,
+        &user_settings,
+        &dialogue,
+    )
+    .await?;
+    match switch {
+        RoleSwitch::Switched { greeting } => {
             bot.edit_message_text(
                 msg.chat.id,
                 msg.id,
@@ -184,19 +437,193 @@ async fn do_switch_role(
             )
             .parse_mode(ParseMode::MarkdownV2)
             .await?;
+            finish_role_switch(&bot, msg.chat.id, &queue, greeting).await?;
         }
-    } else {
+        RoleSwitch::AskVariable { question } => {
+            bot.edit_message_text(msg.chat.id, msg.id, question).await?;
+        }
+        RoleSwitch::NotFound => {
+            bot.send_message(
+                msg.chat.id,
+                escape_markdown_v2_reversed_chars(&format!("Role *{role_name}* not found.")),
+            )
+            .parse_mode(ParseMode::MarkdownV2)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+enum RoleSwitch {
+    Switched {
+        greeting: Option<String>,
+    },
+    /// A template variable has no value yet, the dialogue is now waiting for the answer.
+    AskVariable {
+        question: String,
+    },
+    NotFound,
+}
+
+/// Starts a new session with the role once all of its template variables are known.
+///
+/// Variables are resolved from the built-in ones, the user's settings and `answers`, in that
+/// order. The first missing variable is asked through the dialogue instead.
+#[allow(clippy::too_many_arguments)]
+async fn switch_to_role(
+    chat_id: ChatId,
+    role_name: &str,
+    user: Option<&User>,
+    answers: Variables,
+    roles: &RolesRef,
+    sessions: &SessionsRef,
+    user_settings: &UserSettingsRef,
+    dialogue: &BotDialogue,
+) -> Result<RoleSwitch, anyhow::Error> {
+    let roles = roles.lock().await;
+    let Some(role) = roles.get(role_name) else {
+        return Ok(RoleSwitch::NotFound);
+    };
+
+    let mut variables = builtin_variables(user);
+    if let Some(user) = user {
+        if let Some(settings) = user_settings.lock().await.get(&user.id.0) {
+            variables.extend(settings.variables.clone());
+        }
+    }
+    variables.extend(answers.clone());
+
+    if let Some(variable) = role
+        .placeholders()
+        .into_iter()
+        .find(|name| !variables.contains_key(name))
+    {
+        let question = role
+            .variables
+            .get(&variable)
+            .cloned()
+            .unwrap_or_else(|| format!("Please enter a value for {variable}:"));
+        dialogue
+            .update(State::ReceiveRoleVariable {
+                role_name: role_name.to_string(),
+                variable,
+                values: answers,
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?;
+        return Ok(RoleSwitch::AskVariable { question });
+    }
+
+    let mut history = role.initial_conversation(&variables);
+    let greeting = role
+        .greeting
+        .as_ref()
+        .map(|greeting| template::render(greeting, &variables));
+    if let Some(greeting) = &greeting {
+        history.push(ChatMessage::new_assistant(greeting));
+    }
+    let mut sessions = sessions.lock().await;
+    let previous = sessions.get(&chat_id);
+    let session = Session {
+        role: role_name.to_string(),
+        variables,
+        history,
+        attachments: vec![],
+        voice: previous.and_then(|session| session.voice),
+        generation: previous.map_or(0, |session| session.generation + 1),
+    };
+    sessions.insert(chat_id, session);
+    sessions.save(chat_id);
+    Ok(RoleSwitch::Switched { greeting })
+}
+
+/// What every path that switched a chat to a role does once the switch is confirmed: stops the
+/// answer written for the previous role and greets the user in the new one.
+async fn finish_role_switch(
+    bot: &ThrottledBot,
+    chat_id: ChatId,
+    queue: &ChatQueueRef,
+    greeting: Option<String>,
+) -> Result<(), teloxide::RequestError> {
+    queue.cancel(bot, chat_id).await;
+    if let Some(greeting) = greeting {
+        bot.send_message(chat_id, greeting).await?;
+    }
+    Ok(())
+}
+
+pub fn builtin_variables(user: Option<&User>) -> Variables {
+    let mut variables = Variables::new();
+    variables.insert(
+        "date".to_string(),
+        chrono::Local::now().format("%Y-%m-%d").to_string(),
+    );
+    if let Some(user) = user {
+        variables.insert("user_first_name".to_string(), user.first_name.clone());
+    }
+    variables
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn receive_role_variable(
+    bot: ThrottledBot,
+    msg: Message,
+    (role_name, variable, mut values): (String, String, Variables),
+    roles: RolesRef,
+    sessions: SessionsRef,
+    user_settings: UserSettingsRef,
+    dialogue: BotDialogue,
+    queue: ChatQueueRef,
+) -> HandlerResult {
+    let Some(value) = msg.text() else {
         bot.send_message(
             msg.chat.id,
-            escape_markdown_v2_reversed_chars(&format!("Role *{role_name}* not found.")),
+            format!("Please enter a valid value for {variable}."),
         )
-        .parse_mode(ParseMode::MarkdownV2)
         .await?;
+        return Ok(());
+    };
+    values.insert(variable, value.to_string());
+
+    let switch = switch_to_role(
+        msg.chat.id,
+        &role_name,
+        msg.from(),
+        values,
+        &roles,
+        &sessions,
+        &user_settings,
+        &dialogue,
+    )
+    .await?;
+    match switch {
+        RoleSwitch::Switched { greeting } => {
+            dialogue.update(State::None).await?;
+            bot.send_message(
+                msg.chat.id,
+                escape_markdown_v2_reversed_chars(&format!("Switched to role *{role_name}*.")),
+            )
+            .parse_mode(ParseMode::MarkdownV2)
+            .await?;
+            finish_role_switch(&bot, msg.chat.id, &queue, greeting).await?;
+        }
+        RoleSwitch::AskVariable { question } => {
+            bot.send_message(msg.chat.id, question).await?;
+        }
+        RoleSwitch::NotFound => {
+            dialogue.update(State::None).await?;
+            bot.send_message(msg.chat.id, format!("Role {role_name} no longer exists."))
+                .await?;
+        }
     }
     Ok(())
 }
 
-async fn delete_role(bot: Bot, msg: Message, roles: RolesRef) -> Result<(), anyhow::Error> {
+async fn delete_role(
+    bot: ThrottledBot,
+    msg: Message,
+    roles: RolesRef,
+) -> Result<(), anyhow::Error> {
     send_roles_using_inline_keyboard(
         bot,
         msg,
@@ -209,14 +636,18 @@ async fn delete_role(bot: Bot, msg: Message, roles: RolesRef) -> Result<(), anyh
 }
 
 async fn do_delete_role(
-    bot: Bot,
+    bot: ThrottledBot,
     msg: Message,
     roles: RolesRef,
+    storage: StorageRef,
     role_name: &str,
 ) -> Result<(), anyhow::Error> {
     let mut roles = roles.lock().await;
-    if roles.remove(role_name).is_some() {
-        storages::rewrite_file(&roles).expect("Failed to write roles to file");
+    let mut updated = roles.clone();
+    if updated.remove(role_name).is_some() {
+        if !save_roles(&bot, msg.chat.id, &storage, &mut roles, updated).await? {
+            return Ok(());
+        }
         bot.send_message(
             msg.chat.id,
             escape_markdown_v2_reversed_chars(&format!("Role *{role_name}* deleted successfully.")),
@@ -229,10 +660,197 @@ async fn do_delete_role(
     Ok(())
 }
 
+async fn edit_role(bot: ThrottledBot, msg: Message, roles: RolesRef) -> Result<(), anyhow::Error> {
+    send_roles_using_inline_keyboard(bot, msg, roles, "Choose a role to edit:", Command::EditRole)
+        .await?;
+    Ok(())
+}
+
+/// Handles both steps of the edit keyboard: picking the role, then picking what to change.
+async fn do_edit_role(
+    bot: ThrottledBot,
+    msg: Message,
+    roles: RolesRef,
+    dialogue: BotDialogue,
+    callback_data: &str,
+) -> HandlerResult {
+    if let Some(State::ReceiveEditRoleAction { role_name }) = dialogue.get().await? {
+        if let Ok(action) = serde_json::from_str::<EditRoleAction>(callback_data) {
+            let prompt = match action {
+                EditRoleAction::Replace => "Please enter the new system prompt.",
+                EditRoleAction::Append => "Please enter the text to append to the system prompt.",
+                EditRoleAction::Rename => "Please enter the new name of the role.",
+            };
+            bot.edit_message_text(msg.chat.id, msg.id, prompt).await?;
+            dialogue
+                .update(match action {
+                    EditRoleAction::Rename => State::ReceiveEditRoleNewName { role_name },
+                    _ => State::ReceiveEditRoleSystem { role_name, action },
+                })
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let role_name = callback_data;
+    let roles = roles.lock().await;
+    if let Some(role) = roles.get(role_name) {
+        bot.edit_message_text(
+            msg.chat.id,
+            msg.id,
+            format!(
+                "Current system prompt of role {role_name}:\n\n{}\n\nWhat would you like to change?",
+                role.system
+            ),
+        )
+        .reply_markup(edit_role_actions_keyboard())
+        .await?;
+        dialogue
+            .update(State::ReceiveEditRoleAction {
+                role_name: role_name.to_string(),
+            })
+            .await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            escape_markdown_v2_reversed_chars(&format!("Role *{role_name}* not found.")),
+        )
+        .parse_mode(ParseMode::MarkdownV2)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn receive_edit_role_system(
+    bot: ThrottledBot,
+    msg: Message,
+    roles: RolesRef,
+    (role_name, action): (String, EditRoleAction),
+    sessions: SessionsRef,
+    storage: StorageRef,
+    dialogue: BotDialogue,
+) -> HandlerResult {
+    let Some(text) = msg.text() else {
+        bot.send_message(msg.chat.id, "Please enter a valid role system.")
+            .await?;
+        return Ok(());
+    };
+
+    let mut roles = roles.lock().await;
+    let mut updated = roles.clone();
+    let Some(role) = updated.get_mut(&role_name) else {
+        dialogue.update(State::None).await?;
+        bot.send_message(msg.chat.id, format!("Role {role_name} no longer exists."))
+            .await?;
+        return Ok(());
+    };
+    match action {
+        EditRoleAction::Append => role.system = format!("{}\n{}", role.system, text),
+        _ => role.system = text.to_string(),
+    }
+    let system = role.system.clone();
+    dialogue.update(State::None).await?;
+    if !save_roles(&bot, msg.chat.id, &storage, &mut roles, updated).await? {
+        return Ok(());
+    }
+
+    // Live sessions of the role pick up the new system prompt right away.
+    let mut sessions = sessions.lock().await;
+    let mut changed = vec![];
+    for (chat_id, session) in sessions.iter_mut() {
+        if session.role == role_name {
+            if let Some(first) = session.history.first_mut() {
+                *first = ChatMessage::new_system(&template::render(&system, &session.variables));
+            }
+            changed.push(*chat_id);
+        }
+    }
+    for chat_id in changed {
+        sessions.save(chat_id);
+    }
+    drop(sessions);
+
+    bot.send_message(
+        msg.chat.id,
+        escape_markdown_v2_reversed_chars(&format!(
+            "System prompt of role *{role_name}* updated successfully."
+        )),
+    )
+    .parse_mode(ParseMode::MarkdownV2)
+    .await?;
+    Ok(())
+}
+
+async fn receive_edit_role_new_name(
+    bot: ThrottledBot,
+    msg: Message,
+    roles: RolesRef,
+    role_name: String,
+    sessions: SessionsRef,
+    storage: StorageRef,
+    dialogue: BotDialogue,
+) -> HandlerResult {
+    let Some(new_name) = msg.text().map(str::trim).filter(|name| !name.is_empty()) else {
+        bot.send_message(msg.chat.id, "Please enter a valid role name.")
+            .await?;
+        return Ok(());
+    };
+    if let Err(e) = validate_role_name(new_name) {
+        bot.send_message(msg.chat.id, format!("{e}, please enter another name."))
+            .await?;
+        return Ok(());
+    }
+
+    let mut roles = roles.lock().await;
+    if roles.contains_key(new_name) {
+        bot.send_message(
+            msg.chat.id,
+            format!("Role {new_name} already exists, please enter another name."),
+        )
+        .await?;
+        return Ok(());
+    }
+    let mut updated = roles.clone();
+    let Some(role) = updated.remove(&role_name) else {
+        dialogue.update(State::None).await?;
+        bot.send_message(msg.chat.id, format!("Role {role_name} no longer exists."))
+            .await?;
+        return Ok(());
+    };
+    updated.insert(new_name.to_string(), role);
+    dialogue.update(State::None).await?;
+    if !save_roles(&bot, msg.chat.id, &storage, &mut roles, updated).await? {
+        return Ok(());
+    }
+
+    let mut sessions = sessions.lock().await;
+    let mut changed = vec![];
+    for (chat_id, session) in sessions.iter_mut() {
+        if session.role == role_name {
+            session.role = new_name.to_string();
+            changed.push(*chat_id);
+        }
+    }
+    for chat_id in changed {
+        sessions.save(chat_id);
+    }
+    drop(sessions);
+
+    bot.send_message(
+        msg.chat.id,
+        escape_markdown_v2_reversed_chars(&format!(
+            "Role *{role_name}* renamed to *{new_name}* successfully."
+        )),
+    )
+    .parse_mode(ParseMode::MarkdownV2)
+    .await?;
+    Ok(())
+}
+
 async fn start_new_role_dialogue(
-    bot: Bot,
+    bot: ThrottledBot,
     msg: Message,
-    dialogue: NewRoleDialogue,
+    dialogue: BotDialogue,
 ) -> HandlerResult {
     bot.send_message(
         msg.chat.id,
@@ -243,9 +861,18 @@ async fn start_new_role_dialogue(
     Ok(())
 }
 
-async fn receive_new_role_name(bot: Bot, msg: Message, dialogue: NewRoleDialogue) -> HandlerResult {
+async fn receive_new_role_name(
+    bot: ThrottledBot,
+    msg: Message,
+    dialogue: BotDialogue,
+) -> HandlerResult {
     match msg.text().map(ToOwned::to_owned) {
         Some(role_name) => {
+            if let Err(e) = validate_role_name(&role_name) {
+                bot.send_message(msg.chat.id, format!("{e}, please enter another name."))
+                    .await?;
+                return Ok(());
+            }
             bot.send_message(
                 msg.chat.id,
                 "Next, enter the description of the role. It will be used as a system for ChatGPT.",
@@ -263,35 +890,57 @@ async fn receive_new_role_name(bot: Bot, msg: Message, dialogue: NewRoleDialogue
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn receive_new_role_system(
-    bot: Bot,
+    bot: ThrottledBot,
     msg: Message,
     roles: RolesRef,
     role_name: String,
-    conversation_history: ConversationHistoryRef,
-    current_role: Arc<Mutex<String>>,
-    dialogue: NewRoleDialogue,
+    sessions: SessionsRef,
+    storage: StorageRef,
+    user_settings: UserSettingsRef,
+    dialogue: BotDialogue,
+    queue: ChatQueueRef,
 ) -> HandlerResult {
     match msg.text().map(ToOwned::to_owned) {
         Some(role_system) => {
-            create_role(&roles, &role_name, role_system.clone()).await?;
             dialogue.update(State::None).await?;
+            if !create_role(&bot, msg.chat.id, &roles, &storage, &role_name, role_system).await? {
+                return Ok(());
+            }
 
-            let mut current_role = current_role.lock().await;
-            *current_role = role_name.clone();
-
-            let mut conversation_history = conversation_history.lock().await;
-            conversation_history.clear();
-            conversation_history.push(ChatGptChatFormat::new_system(&role_system));
-
-            bot.send_message(
+            let switch = switch_to_role(
                 msg.chat.id,
-                escape_markdown_v2_reversed_chars(&format!(
-                    "Role *{role_name}* added successfully,r automatically switched to the new role."
-                )),
+                &role_name,
+                msg.from(),
+                Variables::new(),
+                &roles,
+                &sessions,
+                &user_settings,
+                &dialogue,
             )
-            .parse_mode(ParseMode::MarkdownV2)
             .await?;
+            let (text, greeting) = match switch {
+                RoleSwitch::AskVariable { question } => (
+                    format!(
+                        "Role *{role_name}* added successfully. Before switching to it: {question}"
+                    ),
+                    None,
+                ),
+                RoleSwitch::Switched { greeting } => (
+                    format!(
+                        "Role *{role_name}* added successfully, automatically switched to the new role."
+                    ),
+                    Some(greeting),
+                ),
+                RoleSwitch::NotFound => return Ok(()),
+            };
+            bot.send_message(msg.chat.id, escape_markdown_v2_reversed_chars(&text))
+                .parse_mode(ParseMode::MarkdownV2)
+                .await?;
+            if let Some(greeting) = greeting {
+                finish_role_switch(&bot, msg.chat.id, &queue, greeting).await?;
+            }
         }
         None => {
             bot.send_message(msg.chat.id, "Please enter a valid role system.")
@@ -302,85 +951,395 @@ async fn receive_new_role_system(
 }
 
 async fn create_role(
+    bot: &ThrottledBot,
+    chat_id: ChatId,
     roles: &RolesRef,
+    storage: &StorageRef,
     role_name: &str,
     system: String,
-) -> Result<(), anyhow::Error> {
+) -> Result<bool, anyhow::Error> {
     let mut roles = roles.lock().await;
-    roles.insert(role_name.to_string(), Role { system });
-    storages::rewrite_file(&roles).expect("Failed to write roles to file");
-    Ok(())
+    let mut updated = roles.clone();
+    updated.insert(role_name.to_string(), Role::new(system));
+    save_roles(bot, chat_id, storage, &mut roles, updated).await
+}
+
+/// Stores `updated` and only then makes it the current roles, so a failed write changes nothing.
+/// Failures are reported to the user, the return value tells whether the roles were saved.
+pub async fn save_roles(
+    bot: &ThrottledBot,
+    chat_id: ChatId,
+    storage: &StorageRef,
+    roles: &mut Roles,
+    updated: Roles,
+) -> Result<bool, anyhow::Error> {
+    if let Err(e) = storage.save_roles(&updated) {
+        error!("Cannot save roles: {e:#}");
+        bot.send_message(
+            chat_id,
+            format!("Cannot save roles, nothing was changed: {e:#}"),
+        )
+        .await?;
+        return Ok(false);
+    }
+    *roles = updated;
+    Ok(true)
 }
 
 #[allow(clippy::too_many_arguments)]
 async fn command_handler(
-    bot: Bot,
+    bot: ThrottledBot,
     msg: Message,
-    conversation_history: ConversationHistoryRef,
-    current_role: Arc<Mutex<String>>,
+    sessions: SessionsRef,
     roles: RolesRef, // cmd: Command,
+    storage: StorageRef,
     command: Command,
-    dialogue: NewRoleDialogue,
-    settings: Settings,
+    dialogue: BotDialogue,
+    user_settings: UserSettingsRef,
+    queue: ChatQueueRef,
 ) -> HandlerResult {
     match command {
         Command::NewRole => start_new_role_dialogue(bot, msg, dialogue).await?,
+        Command::EditRole => edit_role(bot, msg, roles).await?,
         Command::DeleteRole => delete_role(bot, msg, roles).await?,
         Command::SwitchRole => switch_role(bot, msg, roles).await?,
-        Command::ListRoles => list_roles(&bot, &msg, roles, current_role).await?,
-        Command::Clear => clear_conversation(&bot, &msg, conversation_history).await?,
-        Command::Translate(user_input) => translate(bot, msg, settings, user_input).await?,
-        Command::VariableNamer(scene) => naming_variable(bot, msg, settings, scene).await?,
-        Command::CheckGrammar(sentence) => check_grammar(bot, msg, settings, sentence).await?,
+        Command::ExportRoles(format) => export_roles(bot, msg, roles, format).await?,
+        Command::ImportRoles => start_import_roles(bot, msg, dialogue).await?,
+        Command::ListRoles => list_roles(&bot, &msg, roles, sessions).await?,
+        Command::Clear => clear_conversation(&bot, &msg, sessions, roles, queue).await?,
+        Command::Cancel => cancel(bot, msg, dialogue).await?,
+        Command::Stop => stop_answer(bot, msg, queue).await?,
+        // Commands that need more dependencies have their own branches.
+        Command::Translate(_)
+        | Command::VariableNamer(_)
+        | Command::CheckGrammar(_)
+        | Command::Summarize(_)
+        | Command::Help
+        | Command::Docs(_) => {}
+        Command::SetVariable(input) => {
+            set_variable(bot, msg, user_settings, storage, input).await?
+        }
+        Command::Voice(input) => set_voice(bot, msg, sessions, roles, input).await?,
     }
 
     Ok(())
 }
 
-async fn message_handler(
-    bot: Bot,
-    msg: Message,
-    conversation_history: ConversationHistoryRef,
-    settings: Settings,
-) -> HandlerResult {
+async fn message_handler(bot: ThrottledBot, msg: Message, answerer: Answerer) -> HandlerResult {
+    let config = &answerer.config;
     if let Some(text) = msg.text() {
-        let mut conversation_history = conversation_history.lock().await;
-        conversation_history.push(ChatGptChatFormat::new_user(text));
-        // println!("{:?}", conversation_history);
-        bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::Typing)
+        if let Some(limit) = config.limits.max_message_length {
+            let length = text.chars().count();
+            if length > limit {
+                bot.send_message(
+                    msg.chat.id,
+                    format!("Message too long ({length} characters, the limit is {limit})."),
+                )
+                .await?;
+                return Ok(());
+            }
+        }
+        // ChatGPT can't open links, so a bare link is offered a summary instead.
+        if chat_gpt::parse_link(text).is_some() {
+            bot.send_message(msg.chat.id, "Would you like a summary of this page?")
+                .reply_to_message_id(msg.id)
+                .reply_markup(summarize_link_keyboard())
+                .await?;
+            return Ok(());
+        }
+    } else if msg.photo().is_some() {
+        if !config.openai.supports_images() {
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "The current model, {}, can't read images.",
+                    config.openai.model
+                ),
+            )
             .await?;
-        if let Ok(answer) = ask_chat_gpt(
-            settings.open_ai_api_key.as_str(),
-            conversation_history.clone(),
+            return Ok(());
+        }
+    } else if msg.document().is_none() && !(config.transcription.enabled && is_voice_message(&msg))
+    {
+        return Ok(());
+    }
+    // Answering runs in the chat's queue, so commands such as /clear in the same chat are
+    // handled while ChatGPT answers.
+    let queue = answerer.queue.clone();
+    queue.push(msg, move |messages: Vec<Message>| {
+        let (bot, answerer) = (bot.clone(), answerer.clone());
+        async move {
+            let chat_id = messages[0].chat.id;
+            if let Err(e) = answerer.answer(&bot, &messages).await {
+                error!("Cannot answer message in chat {chat_id}: {e:#}");
+            }
+        }
+    });
+    Ok(())
+}
+
+impl Answerer {
+    /// Asks ChatGPT with a snapshot of the chat's history, and adds the question and the answer
+    /// to the history only once the answer arrived. Locks are held only while taking the
+    /// snapshot and while committing, so other chats and commands aren't blocked in the meantime.
+    ///
+    /// Messages sent in quick succession are asked as a single question.
+    async fn answer(&self, bot: &ThrottledBot, messages: &[Message]) -> Result<(), anyhow::Error> {
+        let Answerer {
+            sessions,
+            roles,
+            storage,
+            config,
+            chat_gpt,
+            queue,
+            rate_limiter,
+            document_store,
+        } = self;
+        let Some(msg) = messages.last() else {
+            return Ok(());
+        };
+        if !check_rate_limits(bot, msg, rate_limiter).await? {
+            return Ok(());
+        }
+        // Voice messages, photos and documents are processed here rather than when received,
+        // so that /stop also stops their download and transcription.
+        let mut texts = vec![];
+        let mut images = vec![];
+        let mut documents = vec![];
+        // Files are attached whole only as long as all of the question's files fit together.
+        let mut attached_chars: usize =
+            sessions
+                .lock()
+                .await
+                .get(&msg.chat.id)
+                .map_or(0, |session| {
+                    session
+                        .attachments
+                        .iter()
+                        .map(|attachment| attachment.chars().count())
+                        .sum()
+                });
+        for message in messages {
+            if let Some(text) = message.text() {
+                texts.push(text.to_string());
+            } else if let Some(sizes) = message.photo() {
+                // Sizes are listed from the smallest to the largest.
+                if let Some(largest) = sizes.last() {
+                    images.push(download_file(bot, &largest.file.id).await?);
+                }
+                texts.extend(message.caption().map(str::to_string));
+            } else if message.document().is_some() {
+                if let Some((file_name, text)) = read_document(bot, message).await? {
+                    let document = attachment(&file_name, &text);
+                    let document_chars = document.chars().count();
+                    if attached_chars + document_chars <= config.documents.max_chars {
+                        attached_chars += document_chars;
+                        documents.push(document);
+                    } else {
+                        store_document(
+                            bot,
+                            message,
+                            chat_gpt,
+                            document_store,
+                            &config.documents,
+                            file_name,
+                            &text,
+                        )
+                        .await?;
+                    }
+                }
+                texts.extend(message.caption().map(str::to_string));
+            } else {
+                texts.extend(transcribe_voice(bot, message, chat_gpt).await?);
+            }
+        }
+        if texts.is_empty() && images.is_empty() {
+            if !documents.is_empty() {
+                self.keep_attachments(bot, msg, documents).await?;
+            }
+            return Ok(());
+        }
+
+        let (mut history, generation, speech, attachments) = {
+            let roles = roles.lock().await;
+            let mut sessions = sessions.lock().await;
+            let session = sessions
+                .entry(msg.chat.id)
+                .or_insert_with(|| new_session(&roles));
+            (
+                limit_history(&session.history, config.limits.max_history_messages),
+                session.generation,
+                answer_speech(session, roles.get(&session.role)),
+                session.attachments.clone(),
+            )
+        };
+        let passages = find_passages(
+            chat_gpt,
+            document_store,
+            &config.documents,
+            msg.chat.id,
+            &texts.join("\n"),
         )
-        .await
-        {
-            conversation_history.push(ChatGptChatFormat::new_assistant(&answer));
-            bot.send_message(msg.chat.id, escape_markdown_v2_reversed_chars(&answer))
-                .parse_mode(ParseMode::MarkdownV2)
+        .await;
+        let text = attachments
+            .iter()
+            .chain(&documents)
+            .chain(&texts)
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let question = match images.is_empty() {
+            true => ChatMessage::new_user(&text),
+            false => ChatMessage::new_user_with_images(&text, &images),
+        };
+        // Passages are only sent with this question, they aren't kept in the history.
+        if !passages.is_empty() {
+            history.push(ChatMessage::new_system(&passages_prompt(&passages)));
+        }
+        // Images are only sent with this question, the history keeps a note of them.
+        let kept_question = question.without_images();
+        history.push(question);
+
+        let status = bot
+            .send_message(msg.chat.id, "Thinking…")
+            .reply_markup(stop_answer_keyboard())
+            .await?;
+        queue.set_status_message(msg.chat.id, status.id);
+        let completion = match ask_chat_gpt(chat_gpt, history).await {
+            Ok(completion) => completion,
+            Err(e) => {
+                bot.edit_message_text(
+                    msg.chat.id,
+                    status.id,
+                    "Sorry, I couldn't get an answer, please try again.",
+                )
                 .await?;
+                return Err(e);
+            }
+        };
+        record_usage(storage, msg, &completion);
+
+        {
+            let mut sessions = sessions.lock().await;
+            let Some(session) = sessions
+                .get_mut(&msg.chat.id)
+                .filter(|session| session.generation == generation)
+            else {
+                info!(
+                    "Dropping answer for chat {}, its conversation was restarted",
+                    msg.chat.id
+                );
+                bot.delete_message(msg.chat.id, status.id).await?;
+                return Ok(());
+            };
+            session.history.push(kept_question);
+            session
+                .history
+                .push(ChatMessage::new_assistant(&completion.content));
+            session.attachments.clear();
+            sessions.save(msg.chat.id);
         }
+
+        // Without the text, it is only shown when the voice message can't be sent.
+        if let Some(speech) = speech.as_ref().filter(|_| !config.speech.with_text) {
+            if send_spoken_answer(bot, msg.chat.id, chat_gpt, &completion.content, speech).await {
+                bot.delete_message(msg.chat.id, status.id).await?;
+                return Ok(());
+            }
+        }
+        let answer = match cited_sources(&completion.content, &passages) {
+            Some(sources) => format!("{}\n\n{sources}", completion.content),
+            None => completion.content.clone(),
+        };
+        bot.edit_message_text(
+            msg.chat.id,
+            status.id,
+            escape_markdown_v2_reversed_chars(&answer),
+        )
+        .parse_mode(ParseMode::MarkdownV2)
+        .await?;
+        if let Some(speech) = speech.as_ref().filter(|_| config.speech.with_text) {
+            send_spoken_answer(bot, msg.chat.id, chat_gpt, &completion.content, speech).await;
+        }
+        Ok(())
     }
-    Ok(())
+
+    /// Keeps files uploaded without a question for the chat's next question.
+    async fn keep_attachments(
+        &self,
+        bot: &ThrottledBot,
+        msg: &Message,
+        documents: Vec<String>,
+    ) -> Result<(), anyhow::Error> {
+        {
+            let roles = self.roles.lock().await;
+            let mut sessions = self.sessions.lock().await;
+            let session = sessions
+                .entry(msg.chat.id)
+                .or_insert_with(|| new_session(&roles));
+            session.attachments.extend(documents);
+            sessions.save(msg.chat.id);
+        }
+        bot.send_message(
+            msg.chat.id,
+            "File received. What would you like to know about it?",
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Tells the user when to retry if a rate limit was reached, returns whether ChatGPT may be asked.
+pub async fn check_rate_limits(
+    bot: &ThrottledBot,
+    msg: &Message,
+    rate_limiter: &RateLimiter,
+) -> Result<bool, teloxide::RequestError> {
+    match rate_limiter.check(msg.from().map(|user| user.id), msg.chat.id) {
+        Ok(()) => Ok(true),
+        Err(throttled) => {
+            bot.send_message(msg.chat.id, throttled.message()).await?;
+            Ok(false)
+        }
+    }
+}
+
+/// Keeps the leading system prompt and at most `limit` of the most recent messages.
+/// The history sent with a question. Images sent with earlier questions are left out, which
+/// sessions saved by older versions of the bot may still hold.
+fn limit_history(history: &[ChatMessage], limit: Option<usize>) -> Vec<ChatMessage> {
+    let limited = match limit {
+        Some(limit) if history.len() > limit + 1 => {
+            let mut limited = history[..1].to_vec();
+            limited.extend_from_slice(&history[history.len() - limit..]);
+            limited
+        }
+        _ => history.to_vec(),
+    };
+    limited.iter().map(ChatMessage::without_images).collect()
 }
 
 async fn list_roles(
-    bot: &Bot,
+    bot: &ThrottledBot,
     msg: &Message,
     roles: RolesRef,
-    current_role: Arc<Mutex<String>>,
+    sessions: SessionsRef,
 ) -> Result<(), anyhow::Error> {
     let roles = roles.lock().await;
-    let current_role = current_role.lock().await;
+    let current_role = match sessions.lock().await.get(&msg.chat.id) {
+        Some(session) => session.role.clone(),
+        None => get_default_role(&roles).0,
+    };
     let roles_list = roles
         .iter()
         .enumerate()
         .map(|(index, (name, role))| {
             format!(
-                "{}. {underline}*{name}*: {}{underline}",
+                "{}. {underline}*{}*: {}{underline}",
                 index + 1,
-                role.system,
-                underline = if name == &*current_role { "__" } else { "" }
+                role.display_name(name),
+                role.summary(),
+                underline = if *name == current_role { "__" } else { "" }
             )
         })
         .collect::<Vec<String>>();
@@ -394,12 +1353,22 @@ async fn list_roles(
 }
 
 async fn callback_handler(
-    bot: Bot,
+    bot: ThrottledBot,
     q: CallbackQuery,
-    conversation_history: ConversationHistoryRef,
-    roles: RolesRef,
-    current_role: Arc<Mutex<String>>,
+    answerer: Answerer,
+    user_settings: UserSettingsRef,
+    dialogue: BotDialogue,
 ) -> HandlerResult {
+    let Answerer {
+        sessions,
+        roles,
+        storage,
+        config,
+        chat_gpt,
+        queue,
+        rate_limiter,
+        ..
+    } = answerer;
     if let Some(callback_data) = q.data {
         bot.answer_callback_query(q.id).await?;
         if q.message.is_none() {
@@ -414,15 +1383,59 @@ async fn callback_handler(
         if let Ok(command) = serde_json::from_str::<Command>(command) {
             match command {
                 Command::DeleteRole => {
-                    do_delete_role(bot, q.message.unwrap(), roles, callback_data).await?;
+                    do_delete_role(bot, q.message.unwrap(), roles, storage, callback_data).await?;
                 }
                 Command::SwitchRole => {
                     do_switch_role(
                         bot,
                         q.message.unwrap(),
-                        conversation_history,
+                        &q.from,
+                        sessions,
                         roles,
-                        current_role,
+                        user_settings,
+                        dialogue,
+                        queue,
+                        callback_data,
+                    )
+                    .await?;
+                }
+                Command::Stop => {
+                    queue.cancel(&bot, q.message.unwrap().chat.id).await;
+                }
+                Command::EditRole => {
+                    do_edit_role(bot, q.message.unwrap(), roles, dialogue, callback_data).await?;
+                }
+                Command::Summarize(_) => {
+                    let message = q.message.unwrap();
+                    let link = message
+                        .reply_to_message()
+                        .and_then(|link_message| Some((link_message, link_message.text()?)));
+                    match link {
+                        Some((link_message, text)) => {
+                            summarize(
+                                bot,
+                                link_message.clone(),
+                                config,
+                                chat_gpt,
+                                storage,
+                                text.to_string(),
+                                rate_limiter,
+                            )
+                            .await?;
+                        }
+                        None => {
+                            bot.send_message(message.chat.id, "The link is no longer available.")
+                                .await?;
+                        }
+                    }
+                }
+                Command::ImportRoles => {
+                    do_import_roles(
+                        bot,
+                        q.message.unwrap(),
+                        roles,
+                        storage,
+                        dialogue,
                         callback_data,
                     )
                     .await?;
@@ -435,13 +1448,43 @@ async fn callback_handler(
     Ok(())
 }
 
+async fn stop_answer(bot: ThrottledBot, msg: Message, queue: ChatQueueRef) -> HandlerResult {
+    if !queue.cancel(&bot, msg.chat.id).await {
+        bot.send_message(msg.chat.id, "Nothing to stop.").await?;
+    }
+    Ok(())
+}
+
+async fn cancel(bot: ThrottledBot, msg: Message, dialogue: BotDialogue) -> HandlerResult {
+    let text = match dialogue.get().await? {
+        None | Some(State::None) => "Nothing to cancel.",
+        Some(_) => "Cancelled.",
+    };
+    dialogue.update(State::None).await?;
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
+
 async fn clear_conversation(
-    bot: &Bot,
+    bot: &ThrottledBot,
     msg: &Message,
-    conversation_history: ConversationHistoryRef,
+    sessions: SessionsRef,
+    roles: RolesRef,
+    queue: ChatQueueRef,
 ) -> HandlerResult {
-    let mut conversation_history = conversation_history.lock().await;
-    conversation_history.truncate(1);
+    queue.cancel(bot, msg.chat.id).await;
+    let roles = roles.lock().await;
+    let mut sessions = sessions.lock().await;
+    let session = sessions
+        .entry(msg.chat.id)
+        .or_insert_with(|| new_session(&roles));
+    match roles.get(&session.role) {
+        Some(role) => session.history = role.initial_conversation(&session.variables),
+        None => session.history.truncate(1),
+    }
+    session.attachments.clear();
+    session.generation += 1;
+    sessions.save(msg.chat.id);
     bot.send_message(
         msg.chat.id,
         "Conversation history cleared, new session started.",
@@ -451,35 +1494,236 @@ async fn clear_conversation(
 }
 
 async fn translate(
-    bot: Bot,
+    bot: ThrottledBot,
     msg: Message,
-    settings: Settings,
+    config: ConfigRef,
+    chat_gpt: ChatGptRef,
+    storage: StorageRef,
     user_input: String,
+    rate_limiter: RateLimiterRef,
 ) -> HandlerResult {
-    bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::Typing)
-        .await?;
-    let output = chat_gpt::translate(settings.open_ai_api_key.as_str(), user_input).await?;
-    bot.send_message(msg.chat.id, output).await?;
-    Ok(())
+    let conversation = match chat_gpt::translate(&config.commands.translate_language, &user_input) {
+        Ok(conversation) => conversation,
+        Err(e) => return send_usage_error(&bot, &msg, &e).await,
+    };
+    ask_helper(&bot, &msg, &chat_gpt, &storage, &rate_limiter, conversation).await
 }
 
 async fn naming_variable(
-    bot: Bot,
+    bot: ThrottledBot,
     msg: Message,
-    settings: Settings,
+    chat_gpt: ChatGptRef,
+    storage: StorageRef,
     scene: String,
+    rate_limiter: RateLimiterRef,
 ) -> HandlerResult {
+    let conversation = match chat_gpt::naming_variable(&scene) {
+        Ok(conversation) => conversation,
+        Err(e) => return send_usage_error(&bot, &msg, &e).await,
+    };
+    ask_helper(&bot, &msg, &chat_gpt, &storage, &rate_limiter, conversation).await
+}
+
+async fn check_grammar(
+    bot: ThrottledBot,
+    msg: Message,
+    config: ConfigRef,
+    chat_gpt: ChatGptRef,
+    storage: StorageRef,
+    scene: String,
+    rate_limiter: RateLimiterRef,
+) -> HandlerResult {
+    let conversation = match chat_gpt::check_grammar(&config.commands.grammar_language, &scene) {
+        Ok(conversation) => conversation,
+        Err(e) => return send_usage_error(&bot, &msg, &e).await,
+    };
+    ask_helper(&bot, &msg, &chat_gpt, &storage, &rate_limiter, conversation).await
+}
+
+/// Asks a helper command's conversation once the rate limits allow it. The arguments are checked
+/// before, so that mistyped commands don't count against the limits.
+async fn ask_helper(
+    bot: &ThrottledBot,
+    msg: &Message,
+    chat_gpt: &ChatGptClient,
+    storage: &StorageRef,
+    rate_limiter: &RateLimiter,
+    conversation: Vec<ChatMessage>,
+) -> HandlerResult {
+    if !check_rate_limits(bot, msg, rate_limiter).await? {
+        return Ok(());
+    }
     bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::Typing)
         .await?;
-    let output = chat_gpt::naming_variable(settings.open_ai_api_key.as_str(), scene).await?;
-    bot.send_message(msg.chat.id, output).await?;
-    Ok(())
+    let result = ask_chat_gpt(chat_gpt, conversation).await;
+    send_completion(bot, msg, storage, result).await
 }
 
-async fn check_grammar(bot: Bot, msg: Message, settings: Settings, scene: String) -> HandlerResult {
+async fn summarize(
+    bot: ThrottledBot,
+    msg: Message,
+    config: ConfigRef,
+    chat_gpt: ChatGptRef,
+    storage: StorageRef,
+    input: String,
+    rate_limiter: RateLimiterRef,
+) -> HandlerResult {
+    let request = match chat_gpt::parse_summary_request(&input) {
+        Ok(request) => request,
+        Err(e) => return send_usage_error(&bot, &msg, &e).await,
+    };
+    if !check_rate_limits(&bot, &msg, &rate_limiter).await? {
+        return Ok(());
+    }
     bot.send_chat_action(msg.chat.id, teloxide::types::ChatAction::Typing)
         .await?;
-    let output = chat_gpt::check_grammar(settings.open_ai_api_key.as_str(), scene).await?;
-    bot.send_message(msg.chat.id, output).await?;
+    match chat_gpt::summarize(&chat_gpt, config.commands.summary_max_tokens, request).await {
+        Ok(completion) => send_completion(&bot, &msg, &storage, Ok(completion)).await,
+        Err(e) => {
+            error!("Cannot summarize in chat {}: {e:#}", msg.chat.id);
+            bot.send_message(msg.chat.id, format!("Cannot summarize: {e:#}"))
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Sends a helper command's answer.
+pub async fn send_completion(
+    bot: &ThrottledBot,
+    msg: &Message,
+    storage: &StorageRef,
+    result: anyhow::Result<Completion>,
+) -> HandlerResult {
+    let completion = result?;
+    record_usage(storage, msg, &completion);
+    bot.send_message(msg.chat.id, completion.content).await?;
+    Ok(())
+}
+
+/// Tells the user what was wrong with a command's arguments.
+pub async fn send_usage_error(
+    bot: &ThrottledBot,
+    msg: &Message,
+    usage_error: &UsageError,
+) -> HandlerResult {
+    bot.send_message(
+        msg.chat.id,
+        format!("{usage_error}. Send /help for the command's options."),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn help(
+    bot: ThrottledBot,
+    msg: Message,
+    config: ConfigRef,
+    prompt_tools: PromptToolsRef,
+) -> HandlerResult {
+    bot.send_message(msg.chat.id, help_text(&config, &prompt_tools))
+        .await?;
     Ok(())
 }
+
+/// The commands, including the prompt tools, followed by the options of those that take any.
+fn help_text(config: &Config, prompt_tools: &PromptTools) -> String {
+    let mut text = Command::descriptions().to_string();
+    for (name, tool) in prompt_tools {
+        text.push_str(&format!("\n/{name} — {}", tool.description.trim()));
+    }
+    let mut options = vec![
+        (
+            "trans".to_string(),
+            chat_gpt::translate_options(&config.commands.translate_language),
+        ),
+        (
+            "gramcheck".to_string(),
+            chat_gpt::grammar_options(&config.commands.grammar_language),
+        ),
+        ("summarize".to_string(), chat_gpt::summary_options()),
+    ];
+    options.extend(
+        prompt_tools
+            .iter()
+            .map(|(name, tool)| (name.clone(), tool.options()))
+            .filter(|(_, options)| !options.is_empty()),
+    );
+    for (command, options) in options {
+        text.push_str(&format!(
+            "\n\nOptions of /{command}:\n{}",
+            describe_options(&options)
+        ));
+    }
+    text.push_str(
+        "\n\nOptions go before the text, as in /trans --lang ja --formal Good morning. \
+        Quote values that contain spaces. Text that starts with a dash and a letter is read as \
+        options, so put -- before it, as in /trans -- -v means verbose.",
+    );
+    text
+}
+
+async fn set_variable(
+    bot: ThrottledBot,
+    msg: Message,
+    user_settings: UserSettingsRef,
+    storage: StorageRef,
+    input: String,
+) -> HandlerResult {
+    let Some(user) = msg.from() else {
+        return Ok(());
+    };
+    let mut user_settings = user_settings.lock().await;
+    let settings = user_settings.entry(user.id.0).or_default();
+
+    let mut parts = input.trim().splitn(2, ' ');
+    let text = match (parts.next().filter(|name| !name.is_empty()), parts.next()) {
+        (None, _) if settings.variables.is_empty() => "No variables set.".to_string(),
+        (None, _) => {
+            let mut variables = settings
+                .variables
+                .iter()
+                .map(|(name, value)| format!("{name} = {value}"))
+                .collect::<Vec<_>>();
+            variables.sort();
+            format!("Variables:\n{}", variables.join("\n"))
+        }
+        (Some(name), None) => {
+            settings.variables.remove(name);
+            storage.save_user_settings(user.id.0, settings)?;
+            format!("Variable {name} removed.")
+        }
+        (Some(name), Some(value)) => {
+            settings
+                .variables
+                .insert(name.to_string(), value.trim().to_string());
+            storage.save_user_settings(user.id.0, settings)?;
+            format!("Variable {name} set to {}.", value.trim())
+        }
+    };
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
+
+/// Usage is bookkeeping, so failing to record it shouldn't fail the reply.
+fn record_usage(storage: &StorageRef, msg: &Message, completion: &Completion) {
+    record_user_usage(storage, msg.from().map(|user| user.id), completion);
+}
+
+pub fn record_user_usage(storage: &StorageRef, user_id: Option<UserId>, completion: &Completion) {
+    let usage = Usage {
+        date: chrono::Local::now().format("%Y-%m-%d").to_string(),
+        user_id: user_id.map(|user_id| user_id.0).unwrap_or_default(),
+        model: completion.model.clone(),
+        requests: 1,
+        prompt_tokens: completion.prompt_tokens,
+        completion_tokens: completion.completion_tokens,
+    };
+    // File backends rewrite the whole usage file, which shouldn't hold up the handler.
+    let storage = storage.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = storage.record_usage(&usage) {
+            error!("Cannot record usage: {e:#}");
+        }
+    });
+}
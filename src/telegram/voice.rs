@@ -0,0 +1,126 @@
+use log::error;
+use teloxide::prelude::*;
+use teloxide::types::{ChatAction, InputFile};
+
+use crate::chat_gpt::{synthesize_speech, transcribe, ChatGptClient};
+use crate::storages::{Role, Session, Speech};
+use crate::telegram::message_helper::{download_file, MAX_DOWNLOAD_SIZE};
+use crate::telegram::session_store::SessionsRef;
+use crate::telegram::startup::{new_session, HandlerResult, RolesRef, ThrottledBot};
+
+pub fn is_voice_message(msg: &Message) -> bool {
+    msg.voice().is_some() || msg.audio().is_some()
+}
+
+/// Transcribes a voice or audio message and echoes the transcript as a reply, so the user sees
+/// what ChatGPT is asked. Returns `None`, after telling the user why, when there's nothing to ask.
+pub async fn transcribe_voice(
+    bot: &ThrottledBot,
+    msg: &Message,
+    chat_gpt: &ChatGptClient,
+) -> Result<Option<String>, anyhow::Error> {
+    let (file, file_name) = match (msg.voice(), msg.audio()) {
+        (Some(voice), _) => (&voice.file, "voice.ogg".to_string()),
+        (None, Some(audio)) => (
+            &audio.file,
+            audio
+                .file_name
+                .clone()
+                .unwrap_or_else(|| "audio.mp3".to_string()),
+        ),
+        (None, None) => return Ok(None),
+    };
+    if file.size > MAX_DOWNLOAD_SIZE {
+        bot.send_message(msg.chat.id, "This recording is too large to transcribe.")
+            .reply_to_message_id(msg.id)
+            .await?;
+        return Ok(None);
+    }
+
+    let transcript = async {
+        let audio = download_file(bot, &file.id).await?;
+        transcribe(chat_gpt, audio, file_name).await
+    }
+    .await;
+    let reply = match &transcript {
+        Ok(text) if !text.is_empty() => format!("🎤 {text}"),
+        Ok(_) => "I couldn't hear anything in this recording.".to_string(),
+        Err(e) => {
+            error!("Cannot transcribe message in chat {}: {e:#}", msg.chat.id);
+            "Sorry, I couldn't transcribe this recording, please try again.".to_string()
+        }
+    };
+    bot.send_message(msg.chat.id, reply)
+        .reply_to_message_id(msg.id)
+        .await?;
+    Ok(transcript.ok().filter(|text| !text.is_empty()))
+}
+
+/// How the chat's answers are read out, or `None` when they aren't. The chat's /voice setting
+/// wins over the role's.
+pub fn answer_speech(session: &Session, role: Option<&Role>) -> Option<Speech> {
+    let speech = role.and_then(|role| role.speech.clone());
+    match session.voice {
+        Some(true) => Some(speech.unwrap_or_default()),
+        Some(false) => None,
+        None => speech,
+    }
+}
+
+/// Sends `text` as a voice message, returning whether it was sent. Failures are only logged, as
+/// the answer can still be shown as text.
+pub async fn send_spoken_answer(
+    bot: &ThrottledBot,
+    chat_id: ChatId,
+    chat_gpt: &ChatGptClient,
+    text: &str,
+    speech: &Speech,
+) -> bool {
+    let result = async {
+        bot.send_chat_action(chat_id, ChatAction::RecordVoice)
+            .await?;
+        let audio = synthesize_speech(chat_gpt, text, speech).await?;
+        bot.send_voice(chat_id, InputFile::memory(audio).file_name("answer.ogg"))
+            .await?;
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    if let Err(e) = &result {
+        error!("Cannot send voice answer in chat {chat_id}: {e:#}");
+    }
+    result.is_ok()
+}
+
+/// Turns voice answers on or off for the chat, or tells whether they are on.
+pub async fn set_voice(
+    bot: ThrottledBot,
+    msg: Message,
+    sessions: SessionsRef,
+    roles: RolesRef,
+    input: String,
+) -> HandlerResult {
+    let roles = roles.lock().await;
+    let mut sessions = sessions.lock().await;
+    let session = sessions
+        .entry(msg.chat.id)
+        .or_insert_with(|| new_session(&roles));
+    let text = match input.trim().to_lowercase().as_str() {
+        "on" => {
+            session.voice = Some(true);
+            sessions.save(msg.chat.id);
+            "Voice answers turned on for this chat."
+        }
+        "off" => {
+            session.voice = Some(false);
+            sessions.save(msg.chat.id);
+            "Voice answers turned off for this chat."
+        }
+        "" => match answer_speech(session, roles.get(&session.role)) {
+            Some(_) => "Voice answers are on, turn them off with /voice off.",
+            None => "Voice answers are off, turn them on with /voice on.",
+        },
+        _ => "Usage: /voice on|off",
+    };
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use std::convert::Infallible;
+
+use anyhow::Context;
+use log::{error, info};
+use teloxide::prelude::*;
+use teloxide::update_listeners::webhooks::{axum_no_setup, Options};
+use teloxide::update_listeners::UpdateListener;
+use url::Url;
+
+use crate::config::WebhookConfig;
+use crate::telegram::startup::ThrottledBot;
+
+/// Registers `url` with Telegram and serves the updates it receives on `config.listen`.
+/// The webhook is removed again when the listener stops.
+pub async fn webhook_listener(
+    bot: ThrottledBot,
+    config: &WebhookConfig,
+    url: Url,
+) -> Result<impl UpdateListener<Err = Infallible>, anyhow::Error> {
+    // Options' URL only decides the path served locally, Telegram is given the public one.
+    let mut local_url = url.clone();
+    if let Some(path) = &config.path {
+        local_url.set_path(path);
+    }
+    let mut options = Options::new(config.listen, local_url);
+    if let Some(secret_token) = &config.secret_token {
+        options = options.secret_token(secret_token.clone());
+    }
+    let secret_token = options.get_or_gen_secret_token().to_string();
+
+    let server = axum::Server::try_bind(&config.listen)
+        .with_context(|| format!("Cannot listen on {}", config.listen))?;
+    bot.set_webhook(url.clone())
+        .secret_token(secret_token)
+        .await
+        .context("Cannot set the webhook")?;
+    info!("Receiving updates through the webhook {url}");
+
+    let (mut listener, stop_flag, router) = axum_no_setup(options);
+    let stop_token = listener.stop_token();
+    tokio::spawn(async move {
+        let result = server
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(stop_flag)
+            .await;
+        if let Err(e) = result {
+            error!("Webhook server error: {e}");
+            stop_token.stop();
+        }
+        if let Err(e) = bot.delete_webhook().await {
+            error!("Cannot delete the webhook: {e}");
+        }
+    });
+
+    Ok(listener)
+}
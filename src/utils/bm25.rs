@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+/// BM25 parameters, the usual defaults.
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+/// An in-memory BM25 index for ranking passages by the words they share with a query.
+#[derive(Default)]
+pub struct Bm25Index {
+    /// Term counts of each indexed text.
+    term_counts: Vec<HashMap<String, u32>>,
+    lengths: Vec<usize>,
+    average_length: f64,
+    /// Number of texts each term appears in.
+    document_frequency: HashMap<String, usize>,
+}
+
+impl Bm25Index {
+    pub fn new<'a>(texts: impl IntoIterator<Item = &'a str>) -> Bm25Index {
+        let mut index = Bm25Index::default();
+        for text in texts {
+            let terms = tokenize(text);
+            let mut counts: HashMap<String, u32> = HashMap::new();
+            for term in &terms {
+                *counts.entry(term.clone()).or_default() += 1;
+            }
+            for term in counts.keys() {
+                *index.document_frequency.entry(term.clone()).or_default() += 1;
+            }
+            index.lengths.push(terms.len());
+            index.term_counts.push(counts);
+        }
+        if !index.lengths.is_empty() {
+            index.average_length =
+                index.lengths.iter().sum::<usize>() as f64 / index.lengths.len() as f64;
+        }
+        index
+    }
+
+    /// Indices of the best matching texts with their scores, best first. Texts sharing no term
+    /// with the query are left out.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<(usize, f64)> {
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+        let count = self.term_counts.len() as f64;
+        let mut scores = vec![];
+        for (i, counts) in self.term_counts.iter().enumerate() {
+            let length_ratio = self.lengths[i] as f64 / self.average_length.max(1.0);
+            let mut score = 0.0;
+            for term in &terms {
+                let Some(&frequency) = counts.get(term) else {
+                    continue;
+                };
+                let frequency = frequency as f64;
+                let documents = self.document_frequency[term] as f64;
+                let idf = (1.0 + (count - documents + 0.5) / (documents + 0.5)).ln();
+                score +=
+                    idf * frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length_ratio));
+            }
+            if score > 0.0 {
+                scores.push((i, score));
+            }
+        }
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scores.truncate(limit);
+        scores
+    }
+}
+
+/// Lowercased words, with each CJK character as a term of its own since those languages don't
+/// separate words with spaces.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut terms = vec![];
+    let mut word = String::new();
+    for c in text.chars() {
+        if is_cjk(c) {
+            if !word.is_empty() {
+                terms.push(std::mem::take(&mut word));
+            }
+            terms.push(c.to_string());
+        } else if c.is_alphanumeric() {
+            word.extend(c.to_lowercase());
+        } else if !word.is_empty() {
+            terms.push(std::mem::take(&mut word));
+        }
+    }
+    if !word.is_empty() {
+        terms.push(word);
+    }
+    terms
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}' // Hiragana and Katakana
+        | '\u{3400}'..='\u{4DBF}' // CJK Extension A
+        | '\u{4E00}'..='\u{9FFF}' // CJK Unified Ideographs
+        | '\u{AC00}'..='\u{D7AF}' // Hangul syllables
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenizes_words_lowercased() {
+        assert_eq!(
+            tokenize("Hello, WORLD! it's 2024"),
+            ["hello", "world", "it", "s", "2024"]
+        );
+        assert_eq!(tokenize("Ünïcode Straße"), ["ünïcode", "straße"]);
+        assert!(tokenize("  ,.!? ").is_empty());
+    }
+
+    #[test]
+    fn tokenizes_each_cjk_character() {
+        assert_eq!(tokenize("你好世界"), ["你", "好", "世", "界"]);
+        assert_eq!(tokenize("こんにちは"), ["こ", "ん", "に", "ち", "は"]);
+        assert_eq!(tokenize("안녕"), ["안", "녕"]);
+        assert_eq!(tokenize("㐀"), ["㐀"]);
+    }
+
+    #[test]
+    fn splits_words_next_to_cjk_characters() {
+        assert_eq!(
+            tokenize("使用Rust编写，version 2"),
+            ["使", "用", "rust", "编", "写", "version", "2"]
+        );
+        assert_eq!(tokenize("GPT模型"), ["gpt", "模", "型"]);
+    }
+
+    #[test]
+    fn ranks_matching_texts() {
+        let index = Bm25Index::new([
+            "The cat sat on the mat",
+            "Dogs chase cats",
+            "向量数据库用于检索",
+            "数据 and more data",
+        ]);
+        assert_eq!(index.search("cat mat", 10)[0].0, 0);
+        let results = index.search("检索", 10);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, 2);
+        let results = index.search("数据", 10);
+        assert_eq!(results.len(), 2);
+        assert!(index.search("nothing here", 10).is_empty());
+        assert_eq!(index.search("data 数据 cat", 1).len(), 1);
+    }
+
+    #[test]
+    fn searches_an_empty_index() {
+        let index = Bm25Index::new([]);
+        assert!(index.search("anything", 5).is_empty());
+        let index = Bm25Index::new([""]);
+        assert!(index.search("anything", 5).is_empty());
+    }
+}
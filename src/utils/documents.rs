@@ -0,0 +1,108 @@
+use anyhow::Context;
+
+/// Extracts the text of an uploaded file: PDFs are parsed, anything else must be UTF-8 text,
+/// such as logs, source code or Markdown.
+pub fn extract_text(
+    file_name: &str,
+    mime_type: Option<&str>,
+    contents: &[u8],
+) -> anyhow::Result<String> {
+    let is_pdf = mime_type == Some("application/pdf") || file_name.to_lowercase().ends_with(".pdf");
+    if is_pdf {
+        return pdf_extract::extract_text_from_mem(contents).context("Cannot read the PDF");
+    }
+    let text = std::str::from_utf8(contents)
+        .ok()
+        .filter(|text| !text.contains('\0'))
+        .context("Only text files and PDFs can be read")?;
+    Ok(text.to_string())
+}
+
+/// Splits `text` into chunks of at most about `max_chars` characters, breaking between lines
+/// where possible.
+pub fn chunk_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    let mut chunk_chars = 0;
+    for line in text.lines() {
+        let line_chars = line.chars().count();
+        if chunk_chars > 0 && chunk_chars + line_chars > max_chars {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_chars = 0;
+        }
+        if line_chars > max_chars {
+            // A line longer than a chunk, as in minified code or PDFs without line breaks.
+            let chars = line.chars().collect::<Vec<_>>();
+            for part in chars.chunks(max_chars) {
+                chunks.push(part.iter().collect());
+            }
+            continue;
+        }
+        chunk.push_str(line);
+        chunk.push('\n');
+        chunk_chars += line_chars + 1;
+    }
+    chunks.push(chunk);
+    chunks.retain(|chunk| !chunk.trim().is_empty());
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_short_text_in_one_chunk() {
+        assert_eq!(chunk_text("one\ntwo\n", 100), ["one\ntwo\n"]);
+    }
+
+    #[test]
+    fn breaks_between_lines() {
+        let chunks = chunk_text("aaaa\nbbbb\ncccc\ndddd", 10);
+        assert_eq!(chunks, ["aaaa\nbbbb\n", "cccc\ndddd\n"]);
+    }
+
+    #[test]
+    fn chunks_neither_overlap_nor_lose_lines() {
+        let text = (0..200)
+            .map(|i| format!("line {i} of the document"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let chunks = chunk_text(&text, 100);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            // A chunk's lines fit in `max_chars`, not counting the final line break.
+            assert!(chunk.chars().count() <= 101, "{chunk:?} is too long");
+        }
+        assert_eq!(chunks.concat(), text + "\n");
+    }
+
+    #[test]
+    fn splits_long_lines() {
+        let line = "x".repeat(25);
+        let chunks = chunk_text(&format!("short\n{line}\nend"), 10);
+        assert_eq!(
+            chunks,
+            ["short\n", "xxxxxxxxxx", "xxxxxxxxxx", "xxxxx", "end\n"]
+        );
+    }
+
+    #[test]
+    fn splits_multi_byte_text_on_character_boundaries() {
+        let text = "数据库检索增强生成".repeat(3);
+        let chunks = chunk_text(&text, 4);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 4));
+        assert_eq!(chunks.concat(), text);
+        assert_eq!(chunks[0], "数据库检");
+
+        let chunks = chunk_text("héllo\nwörld\n😀😀😀", 6);
+        assert_eq!(chunks, ["héllo\n", "wörld\n", "😀😀😀\n"]);
+    }
+
+    #[test]
+    fn drops_blank_chunks() {
+        assert!(chunk_text("", 10).is_empty());
+        assert!(chunk_text("\n\n   \n", 10).is_empty());
+        assert!(chunk_text("\n\n", 1).is_empty());
+    }
+}
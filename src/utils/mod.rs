@@ -1 +1,5 @@
-pub mod telegram_utils;
\ No newline at end of file
+pub mod bm25;
+pub mod documents;
+pub mod options;
+pub mod telegram_utils;
+pub mod template;
@@ -0,0 +1,336 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// An option a command accepts. Options with a value are written `-l ja`, `-lja`, `--lang ja`
+/// or `--lang=ja`; flags take no value, and short ones can be combined, as in `-fv`.
+#[derive(Clone, Debug)]
+pub struct OptionSpec {
+    pub name: String,
+    pub short: Option<char>,
+    pub takes_value: bool,
+    pub default: Option<String>,
+    pub description: String,
+}
+
+impl OptionSpec {
+    pub fn value(name: &str, short: Option<char>, description: &str) -> OptionSpec {
+        OptionSpec {
+            name: name.to_string(),
+            short,
+            takes_value: true,
+            default: None,
+            description: description.to_string(),
+        }
+    }
+
+    pub fn flag(name: &str, short: Option<char>, description: &str) -> OptionSpec {
+        OptionSpec {
+            takes_value: false,
+            ..OptionSpec::value(name, short, description)
+        }
+    }
+
+    pub fn with_default(self, default: impl Into<String>) -> OptionSpec {
+        OptionSpec {
+            default: Some(default.into()),
+            ..self
+        }
+    }
+
+    /// How the option is written, e.g. `-l, --lang <value>`.
+    fn synopsis(&self) -> String {
+        let mut synopsis = match self.short {
+            Some(short) => format!("-{short}, --{}", self.name),
+            None => format!("--{}", self.name),
+        };
+        if self.takes_value {
+            synopsis.push_str(" <value>");
+        }
+        synopsis
+    }
+}
+
+/// The options given to a command, and the text after them.
+#[derive(Debug, Default)]
+pub struct ParsedArgs {
+    values: HashMap<String, String>,
+    flags: HashSet<String>,
+    pub body: String,
+}
+
+impl ParsedArgs {
+    /// The option's value, or its default when it wasn't given.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains(name)
+    }
+}
+
+/// A mistake in a command's arguments, shown to the user who made it.
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Reads the options at the start of `input`. Options end at the first word that doesn't start
+/// with `-` and a letter, or after `--`, and the rest of the input is kept as it is as the body.
+/// Option values can be quoted with `'` or `"`.
+pub fn parse_args(specs: &[OptionSpec], input: &str) -> Result<ParsedArgs, UsageError> {
+    let mut args = ParsedArgs::default();
+    let mut rest = input.trim_start();
+    loop {
+        if let Some(after) = rest.strip_prefix("--") {
+            if after.is_empty() || after.starts_with(char::is_whitespace) {
+                rest = after.trim_start();
+                break;
+            }
+            let (word, after) = read_word(after)?;
+            let (name, value) = match word.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (word, None),
+            };
+            let spec = specs
+                .iter()
+                .find(|spec| spec.name == name)
+                .ok_or_else(|| unknown_option(&format!("--{name}")))?;
+            rest = match (spec.takes_value, value) {
+                (true, Some(value)) => {
+                    args.values.insert(spec.name.clone(), value);
+                    after
+                }
+                (true, None) => {
+                    let (value, after) = read_value(after, &format!("--{name}"))?;
+                    args.values.insert(spec.name.clone(), value);
+                    after
+                }
+                (false, Some(_)) => {
+                    return Err(UsageError(format!("Option --{name} takes no value")));
+                }
+                (false, None) => {
+                    args.flags.insert(spec.name.clone());
+                    after
+                }
+            };
+        } else if let Some(after) = rest
+            .strip_prefix('-')
+            .filter(|after| after.starts_with(char::is_alphabetic))
+        {
+            let (word, mut after) = read_word(after)?;
+            for (index, short) in word.char_indices() {
+                let spec = specs
+                    .iter()
+                    .find(|spec| spec.short == Some(short))
+                    .ok_or_else(|| unknown_option(&format!("-{short}")))?;
+                if !spec.takes_value {
+                    args.flags.insert(spec.name.clone());
+                    continue;
+                }
+                let glued = &word[index + short.len_utf8()..];
+                let value = if glued.is_empty() {
+                    let (value, rest) = read_value(after, &format!("-{short}"))?;
+                    after = rest;
+                    value
+                } else {
+                    glued.to_string()
+                };
+                args.values.insert(spec.name.clone(), value);
+                break;
+            }
+            rest = after;
+        } else {
+            break;
+        }
+        rest = rest.trim_start();
+    }
+
+    for spec in specs {
+        if let Some(default) = &spec.default {
+            args.values
+                .entry(spec.name.clone())
+                .or_insert_with(|| default.clone());
+        }
+    }
+    args.body = rest.trim_end().to_string();
+    Ok(args)
+}
+
+/// The options' documentation, one per line, as shown by /help.
+pub fn describe_options(specs: &[OptionSpec]) -> String {
+    specs
+        .iter()
+        .map(|spec| {
+            let mut line = format!("  {}  {}", spec.synopsis(), spec.description);
+            if let Some(default) = &spec.default {
+                line.push_str(&format!(" (default: {default})"));
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Text that happens to start with a dash is read as options too, so the error says how to avoid
+/// that.
+fn unknown_option(option: &str) -> UsageError {
+    UsageError(format!(
+        "Unknown option {option}; put `--` before text starting with '-'"
+    ))
+}
+
+fn read_value<'a>(input: &'a str, option: &str) -> Result<(String, &'a str), UsageError> {
+    let input = input.trim_start();
+    if input.is_empty() {
+        return Err(UsageError(format!("Option {option} needs a value")));
+    }
+    read_word(input)
+}
+
+/// The word at the start of `input`, without its quotes, and what follows it.
+fn read_word(input: &str) -> Result<(String, &str), UsageError> {
+    let mut word = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in input.char_indices() {
+        if escaped {
+            word.push(c);
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => word.push(c),
+            (_, '\\') => escaped = true,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, c) if c.is_whitespace() => return Ok((word, &input[index..])),
+            _ => word.push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(UsageError("Unterminated quote".to_string()));
+    }
+    Ok((word, ""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn specs() -> Vec<OptionSpec> {
+        vec![
+            OptionSpec::value("lang", Some('l'), "Language").with_default("English"),
+            OptionSpec::flag("formal", Some('f'), "Formal"),
+            OptionSpec::flag("verbose", Some('v'), "Verbose"),
+        ]
+    }
+
+    fn parse(input: &str) -> ParsedArgs {
+        parse_args(&specs(), input).unwrap()
+    }
+
+    fn parse_error(input: &str) -> String {
+        parse_args(&specs(), input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn combines_short_flags() {
+        let args = parse("-fv Good morning");
+        assert!(args.flag("formal"));
+        assert!(args.flag("verbose"));
+        assert_eq!(args.body, "Good morning");
+    }
+
+    #[test]
+    fn reads_a_short_option_value_glued_or_separate() {
+        assert_eq!(parse("-lja Hello").value("lang"), Some("ja"));
+        assert_eq!(parse("-l ja Hello").value("lang"), Some("ja"));
+        let args = parse("-flja Hello");
+        assert!(args.flag("formal"));
+        assert_eq!(args.value("lang"), Some("ja"));
+        assert_eq!(args.body, "Hello");
+    }
+
+    #[test]
+    fn reads_long_options() {
+        let args = parse("--lang=ja --formal Hello");
+        assert_eq!(args.value("lang"), Some("ja"));
+        assert!(args.flag("formal"));
+        assert_eq!(parse("--lang ja Hello").value("lang"), Some("ja"));
+        assert_eq!(
+            parse_error("--formal=yes Hello"),
+            "Option --formal takes no value"
+        );
+    }
+
+    #[test]
+    fn fills_in_defaults() {
+        let args = parse("Hello");
+        assert_eq!(args.value("lang"), Some("English"));
+        assert!(!args.flag("formal"));
+        assert_eq!(args.body, "Hello");
+    }
+
+    #[test]
+    fn unquotes_and_unescapes_values() {
+        assert_eq!(
+            parse(r#"--lang "Simplified Chinese" Hello"#).value("lang"),
+            Some("Simplified Chinese")
+        );
+        assert_eq!(parse("-l 'a \"b\"' Hello").value("lang"), Some("a \"b\""));
+        assert_eq!(parse(r"-l a\ b Hello").value("lang"), Some("a b"));
+    }
+
+    #[test]
+    fn keeps_the_body_as_it_is() {
+        assert_eq!(
+            parse("-f  Say \"hi\"   -v\nthere ").body,
+            "Say \"hi\"   -v\nthere"
+        );
+    }
+
+    #[test]
+    fn stops_at_double_dash() {
+        let args = parse("-f -- -v means verbose");
+        assert!(args.flag("formal"));
+        assert!(!args.flag("verbose"));
+        assert_eq!(args.body, "-v means verbose");
+    }
+
+    #[test]
+    fn text_without_a_letter_after_the_dash_is_the_body() {
+        assert_eq!(parse("-1 is negative").body, "-1 is negative");
+        assert_eq!(parse("- item").body, "- item");
+    }
+
+    #[test]
+    fn reports_unknown_options_with_a_hint() {
+        assert_eq!(
+            parse_error("-o Hello"),
+            "Unknown option -o; put `--` before text starting with '-'"
+        );
+        assert_eq!(
+            parse_error("--other Hello"),
+            "Unknown option --other; put `--` before text starting with '-'"
+        );
+    }
+
+    #[test]
+    fn reports_missing_values() {
+        assert_eq!(parse_error("--lang"), "Option --lang needs a value");
+        assert_eq!(parse_error("-f -l"), "Option -l needs a value");
+    }
+
+    #[test]
+    fn reports_unterminated_quotes() {
+        assert_eq!(parse_error("--lang \"ja Hello"), "Unterminated quote");
+        assert_eq!(parse_error("-l 'ja Hello"), "Unterminated quote");
+    }
+}
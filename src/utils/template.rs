@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+pub type Variables = HashMap<String, String>;
+
+/// Replaces every `{{name}}` placeholder that has a value in `variables`, leaving the others as is.
+pub fn render(template: &str, variables: &Variables) -> String {
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some((before, name, after)) = next_placeholder(rest) {
+        output.push_str(before);
+        match variables.get(name) {
+            Some(value) => output.push_str(value),
+            None => output.push_str(&rest[before.len()..rest.len() - after.len()]),
+        }
+        rest = after;
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Names of the placeholders in `template`, in order of first appearance.
+pub fn placeholders(template: &str) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    let mut rest = template;
+    while let Some((_, name, after)) = next_placeholder(rest) {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+        rest = after;
+    }
+    names
+}
+
+/// Splits `input` around its first valid placeholder into (text before, name, text after).
+fn next_placeholder(input: &str) -> Option<(&str, &str, &str)> {
+    let mut offset = 0;
+    while let Some(start) = input[offset..].find("{{").map(|i| i + offset) {
+        let end = start + 2 + input[start + 2..].find("}}")?;
+        let name = input[start + 2..end].trim();
+        if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Some((&input[..start], name, &input[end + 2..]));
+        }
+        offset = start + 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs: &[(&str, &str)]) -> Variables {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn replaces_placeholders() {
+        let variables = variables(&[("language", "Japanese"), ("name", "Ann")]);
+        assert_eq!(
+            render("Translate to {{language}} for {{ name }}.", &variables),
+            "Translate to Japanese for Ann."
+        );
+        assert_eq!(render("{{name}}{{name}}", &variables), "AnnAnn");
+    }
+
+    #[test]
+    fn leaves_unresolved_placeholders() {
+        let variables = variables(&[("name", "Ann")]);
+        assert_eq!(
+            render("{{name}} speaks {{ language }}.", &variables),
+            "Ann speaks {{ language }}."
+        );
+    }
+
+    #[test]
+    fn leaves_text_that_is_not_a_placeholder() {
+        let variables = variables(&[("name", "Ann")]);
+        for text in [
+            "{name}",
+            "{{}}",
+            "{{two words}}",
+            "{{name",
+            "fn main() { {{} }",
+            "{{a-b}}",
+        ] {
+            assert_eq!(render(text, &variables), text);
+        }
+        assert_eq!(render("{{{name}}}", &variables), "{{{name}}}");
+        assert_eq!(render("{{x}} {{name}}", &variables), "{{x}} Ann");
+    }
+
+    #[test]
+    fn does_not_expand_placeholders_in_values() {
+        let variables = variables(&[("a", "{{b}}"), ("b", "B"), ("c", "}} {{")]);
+        assert_eq!(render("{{a}} {{b}}", &variables), "{{b}} B");
+        assert_eq!(render("{{c}}{{b}}", &variables), "}} {{B");
+    }
+
+    #[test]
+    fn multi_byte_text_around_placeholders() {
+        let variables = variables(&[("name", "世界")]);
+        assert_eq!(render("你好，{{name}}！", &variables), "你好，世界！");
+    }
+
+    #[test]
+    fn lists_placeholders_once_in_order() {
+        assert_eq!(
+            placeholders("{{b}} {{a}} {{ b }} {{not valid}} {{c}}"),
+            ["b", "a", "c"]
+        );
+        assert!(placeholders("no placeholders {here}").is_empty());
+    }
+}